@@ -0,0 +1,37 @@
+use axum::http::StatusCode;
+use axum::response::Json;
+use axum::response::{IntoResponse, Response};
+use serde_json::json;
+use thiserror::Error;
+
+/// Errors shared by the servers, rendered the same way as their own errors
+#[derive(Error, Debug)]
+pub enum CoreError {
+    #[error("jwt error: {0}")]
+    JWTError(#[from] jwt_simple::Error),
+    #[error("unauthorized:{0}")]
+    Unauthorized(String),
+    #[error("invalid token:{0}")]
+    InvalidToken(String),
+    #[error("unknown key id:{0}")]
+    UnknownKey(String),
+}
+
+impl CoreError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            CoreError::JWTError(_) => StatusCode::FORBIDDEN,
+            CoreError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            CoreError::InvalidToken(_) => StatusCode::FORBIDDEN,
+            CoreError::UnknownKey(_) => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+impl IntoResponse for CoreError {
+    fn into_response(self) -> Response<axum::body::Body> {
+        let status = self.status_code();
+
+        (status, Json(json!({"error":self.to_string()}))).into_response()
+    }
+}
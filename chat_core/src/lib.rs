@@ -0,0 +1,12 @@
+mod error;
+pub mod middlewares;
+pub mod models;
+pub mod utils;
+
+pub use error::CoreError;
+pub use middlewares::{verify_token, TokenVerify};
+pub use models::*;
+pub use utils::{
+    load_config, read_pem, token_kid, DecodingKey, EncodingKey, Jwk, Jwks, PublicKeyConfig,
+    RateLimiter, UserCache, UserClaims,
+};
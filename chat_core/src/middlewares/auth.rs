@@ -0,0 +1,162 @@
+use std::future::Future;
+
+use axum::{
+    extract::{FromRequestParts, Query, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use serde::Deserialize;
+
+use crate::{CoreError, User};
+
+/// Implemented by the server states so that they can share the token verification middleware.
+/// Async since verifying also checks that the session of the token is not revoked. The error
+/// is the response, so that a failing lookup is not mistaken for a bad token
+pub trait TokenVerify {
+    type Error: std::fmt::Debug + IntoResponse;
+    fn verify(&self, token: &str) -> impl Future<Output = Result<User, Self::Error>> + Send;
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenParams {
+    access_token: String,
+}
+
+/// Take the token from the Authorization header, or from the `access_token` query
+/// since EventSource in browsers can't set headers
+pub async fn verify_token<T>(State(state): State<T>, req: Request, next: Next) -> Response
+where
+    T: TokenVerify + Clone + Send + Sync + 'static,
+{
+    let (mut parts, body) = req.into_parts();
+    let token =
+        match TypedHeader::<Authorization<Bearer>>::from_request_parts(&mut parts, &state).await {
+            Ok(TypedHeader(Authorization(bearer))) => bearer.token().to_string(),
+            Err(e) => match Query::<TokenParams>::from_request_parts(&mut parts, &state).await {
+                Ok(Query(params)) => params.access_token,
+                Err(_) => {
+                    let msg = format!("Failed to parse Authorization header: {:?}", e);
+                    tracing::warn!(msg);
+                    return CoreError::Unauthorized(msg).into_response();
+                }
+            },
+        };
+
+    match state.verify(&token).await {
+        Ok(user) => {
+            let mut req = Request::from_parts(parts, body);
+            req.extensions_mut().insert(user);
+            next.run(req).await
+        }
+        Err(e) => {
+            tracing::warn!("Failed to verify token: {:?}", e);
+            e.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{DecodingKey, EncodingKey, UserClaims};
+    use anyhow::Result;
+    use axum::{
+        body::Body, http::StatusCode, middleware::from_fn_with_state, routing::get, Extension,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    #[derive(Clone)]
+    struct AppState(Arc<(EncodingKey, DecodingKey)>);
+
+    impl TokenVerify for AppState {
+        type Error = CoreError;
+
+        async fn verify(&self, token: &str) -> Result<User, Self::Error> {
+            let claims = self.0 .1.verify(token)?;
+            Ok(User::new(claims.uid, "Manon Loki", "manonloki@gmail.com"))
+        }
+    }
+
+    async fn handler(Extension(user): Extension<User>) -> impl IntoResponse {
+        user.id.to_string()
+    }
+
+    #[tokio::test]
+    async fn verify_token_middleware_should_work() -> Result<()> {
+        let ek = EncodingKey::load("dev", include_str!("../../fixtures/encoding.pem"))?;
+        let dk = ek.decoding_key();
+        let token = ek.sign(UserClaims {
+            uid: 1,
+            wid: 1,
+            sid: 1,
+        })?;
+        let state = AppState(Arc::new((ek, dk)));
+
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
+            .with_state(state);
+
+        let req = Request::builder()
+            .uri("/")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let req = Request::builder()
+            .uri(format!("/?access_token={}", token))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let req = Request::builder().uri("/").body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let req = Request::builder()
+            .uri("/")
+            .header("Authorization", "Bearer bad-token")
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        Ok(())
+    }
+
+    /// A verifier whose session store is down
+    #[derive(Clone)]
+    struct DownState;
+
+    impl TokenVerify for DownState {
+        type Error = StatusCode;
+
+        async fn verify(&self, _token: &str) -> Result<User, Self::Error> {
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+
+    #[tokio::test]
+    async fn verify_errors_should_keep_their_status() -> Result<()> {
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(from_fn_with_state(DownState, verify_token::<DownState>))
+            .with_state(DownState);
+
+        let req = Request::builder()
+            .uri("/")
+            .header("Authorization", "Bearer some-token")
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        Ok(())
+    }
+}
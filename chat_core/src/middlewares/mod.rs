@@ -0,0 +1,3 @@
+mod auth;
+
+pub use auth::{verify_token, TokenVerify};
@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{prelude::*, PgConnection, PgPool};
+
+/// pg_notify channels of the events that don't come from a table trigger,
+/// chat_server and notify_server both send them
+pub const CHAT_READ: &str = "chat_read";
+pub const CHAT_TYPING: &str = "chat_typing";
+
+/// Messages of others after the read cursor of a `cm` chat_members row, replies
+/// in threads and deleted messages are not counted
+pub const UNREAD_COUNT: &str = r#"
+    (
+        SELECT COUNT(*) FROM messages m
+        WHERE m.chat_id = cm.chat_id AND m.parent_id IS NULL
+        AND m.deleted_at IS NULL AND m.sender_id <> cm.user_id
+        AND m.id > COALESCE(cm.last_read_message_id, 0)
+    )
+"#;
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct User {
+    pub id: i64,
+    pub ws_id: i64,
+    pub fullname: String,
+    pub email: String,
+    #[serde(skip)]
+    #[sqlx(default)]
+    pub password_hash: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Workspace {
+    pub id: i64,
+    pub name: String,
+    /// the user who created it, none for a workspace left without users by a migration
+    pub owner_id: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type)]
+#[sqlx(type_name = "chat_type", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ChatType {
+    Single,
+    Group,
+    PrivateChannel,
+    PublicChannel,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Chat {
+    pub id: i64,
+    pub ws_id: i64,
+    pub name: String,
+    pub r#type: ChatType,
+    pub members: Vec<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type)]
+#[sqlx(type_name = "chat_member_role", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ChatMemberRole {
+    Owner,
+    Admin,
+    Member,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct ChatMember {
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub role: ChatMemberRole,
+    pub joined_at: DateTime<Utc>,
+    pub last_read_message_id: Option<i64>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Message {
+    pub id: i64,
+    pub chat_id: i64,
+    pub sender_id: i64,
+    pub content: String,
+    pub images: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    /// the message this one replies to, in its thread
+    #[serde(default)]
+    pub parent_id: Option<i64>,
+    /// replies in the thread of this message, only filled in when listing a chat
+    #[sqlx(default)]
+    #[serde(default)]
+    pub reply_count: i64,
+    #[sqlx(default)]
+    #[serde(default)]
+    pub last_reply_at: Option<DateTime<Utc>>,
+    /// when the content was last edited
+    #[serde(default)]
+    pub updated_at: Option<DateTime<Utc>>,
+    /// deleted messages keep their place in the chat, with no content or images
+    #[serde(default)]
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// what is known of the files in `images`, in the same order
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    /// reactions in the order they were first used, as seen by the user listing
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
+}
+
+/// How many users reacted to a message with an emoji
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: i64,
+    /// whether the user the message is shown to is one of them
+    pub me: bool,
+}
+
+/// A previous version of an edited message
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct MessageEdit {
+    pub message_id: i64,
+    pub content: String,
+    pub images: Vec<String>,
+    pub edited_at: DateTime<Utc>,
+}
+
+/// An uploaded file, with the size of images and the url of their thumbnail
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Attachment {
+    pub url: String,
+    /// a smaller PNG, missing when the image is small already
+    pub thumbnail_url: Option<String>,
+    pub mime: String,
+    pub size: i64,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+}
+
+/// The read cursor of a user in a chat
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ChatRead {
+    pub chat_id: i64,
+    pub last_read_message_id: Option<i64>,
+    pub unread_count: i64,
+}
+
+/// A signed in device, access tokens carry its id
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Session {
+    pub id: i64,
+    pub user_id: i64,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// A user typing in a chat, nothing is stored
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Typing {
+    pub chat_id: i64,
+    pub user_id: i64,
+}
+
+impl ChatRead {
+    pub async fn fetch(
+        conn: &mut PgConnection,
+        chat_id: i64,
+        user_id: i64,
+    ) -> Result<Self, sqlx::Error> {
+        let (last_read_message_id, unread_count) = sqlx::query_as(&format!(
+            r#"
+                SELECT cm.last_read_message_id,{}
+                FROM chat_members cm
+                WHERE cm.chat_id = $1 AND cm.user_id = $2
+            "#,
+            UNREAD_COUNT
+        ))
+        .bind(chat_id)
+        .bind(user_id)
+        .fetch_one(conn)
+        .await?;
+
+        Ok(Self {
+            chat_id,
+            last_read_message_id,
+            unread_count,
+        })
+    }
+
+    /// Move the read cursor of a member forward to a message of the chat, and
+    /// tell the devices of the user when it moved. Returns the cursor and
+    /// whether it moved, a cursor never goes back
+    pub async fn mark(
+        conn: &mut PgConnection,
+        chat_id: i64,
+        user_id: i64,
+        message_id: i64,
+    ) -> Result<(Self, bool), sqlx::Error> {
+        let ret = sqlx::query(
+            r#"
+                UPDATE chat_members SET last_read_message_id = $3
+                WHERE chat_id = $1 AND user_id = $2
+                AND COALESCE(last_read_message_id, 0) < $3
+                AND EXISTS(SELECT 1 FROM messages WHERE id = $3 AND chat_id = $1)
+            "#,
+        )
+        .bind(chat_id)
+        .bind(user_id)
+        .bind(message_id)
+        .execute(&mut *conn)
+        .await?;
+        let read = Self::fetch(&mut *conn, chat_id, user_id).await?;
+        let moved = ret.rows_affected() > 0;
+        if moved {
+            let payload = json!({ "user_id": user_id, "read": read });
+            sqlx::query("SELECT pg_notify($1, $2)")
+                .bind(CHAT_READ)
+                .bind(payload.to_string())
+                .execute(conn)
+                .await?;
+        }
+
+        Ok((read, moved))
+    }
+}
+
+impl Session {
+    /// Check that the session of an access token has not been revoked. chat_server
+    /// revokes sessions on signout, refresh token reuse and password change
+    pub async fn is_active(pool: &PgPool, id: i64) -> Result<bool, sqlx::Error> {
+        let (active,): (bool,) = sqlx::query_as(
+            r#"SELECT EXISTS(SELECT 1 FROM sessions WHERE id = $1 AND revoked_at IS NULL)"#,
+        )
+        .bind(id)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(active)
+    }
+}
+
+impl Typing {
+    /// Send the typing to the listeners, members are looked up by notify_server
+    pub async fn notify(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+                SELECT pg_notify($1, json_build_object(
+                    'chat_id', $2::BIGINT,
+                    'user_id', $3::BIGINT
+                )::text)
+            "#,
+        )
+        .bind(CHAT_TYPING)
+        .bind(self.chat_id)
+        .bind(self.user_id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+impl Message {
+    /// Fill in the attachments of messages, files uploaded before attachments
+    /// were recorded are left out
+    pub async fn load_attachments(
+        pool: &PgPool,
+        messages: &mut [Message],
+    ) -> Result<(), sqlx::Error> {
+        let urls: Vec<&str> = messages
+            .iter()
+            .flat_map(|m| m.images.iter().map(|s| s.as_str()))
+            .collect();
+        if urls.is_empty() {
+            return Ok(());
+        }
+
+        let attachments: Vec<Attachment> = sqlx::query_as(
+            r#"
+                SELECT url,thumbnail_url,mime,size,width,height
+                FROM attachments
+                WHERE url = ANY($1)
+            "#,
+        )
+        .bind(&urls)
+        .fetch_all(pool)
+        .await?;
+        let attachments: HashMap<_, _> = attachments
+            .into_iter()
+            .map(|a| (a.url.clone(), a))
+            .collect();
+
+        for message in messages.iter_mut() {
+            message.attachments = message
+                .images
+                .iter()
+                .filter_map(|url| attachments.get(url).cloned())
+                .collect();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl User {
+    pub fn new(id: i64, fullname: &str, email: &str) -> Self {
+        Self {
+            id,
+            ws_id: 1,
+            fullname: fullname.to_string(),
+            email: email.to_string(),
+            password_hash: None,
+            created_at: Utc::now(),
+        }
+    }
+}
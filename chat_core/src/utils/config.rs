@@ -0,0 +1,365 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde_yaml::{Mapping, Value};
+use tracing::{info, warn};
+
+/// Load a yaml config by merging, from lowest to highest precedence:
+///
+/// 1. /etc/config/{name}
+/// 2. ./{name}
+/// 3. the file in env `{prefix}CONFIG`
+/// 4. the file given by the `--config` command line argument
+///
+/// Files may be partial, later ones only override the fields they set. The explicit
+/// sources (3 and 4) must exist. Finally every field can be overridden by env
+/// `{prefix}SECTION__FIELD`, e.g. `CHAT_SERVER__PORT`.
+///
+/// `secrets` are dotted paths, e.g. `auth.sk`, that must not be inherited from
+/// the checked-in ./{name} once any other file is loaded, see [`check_secrets`].
+pub fn load_config<T: DeserializeOwned>(name: &str, prefix: &str, secrets: &[&str]) -> Result<T> {
+    let mut sources = vec![
+        (PathBuf::from("/etc/config").join(name), false),
+        (PathBuf::from(name), false),
+    ];
+    if let Ok(path) = env::var(format!("{}CONFIG", prefix)) {
+        sources.push((PathBuf::from(path), true));
+    }
+    if let Some(path) = config_arg(env::args()) {
+        sources.push((path, true));
+    }
+
+    let layers = read_layers(&sources)?;
+    check_secrets(&layers, Path::new(name), prefix, secrets, env::vars())?;
+    let mut value = merge_layers(layers)?;
+    apply_env_overrides(&mut value, prefix, env::vars());
+
+    Ok(serde_yaml::from_value(value)?)
+}
+
+/// Read a PEM either inline or, if it doesn't look like one, from the file it points to
+pub fn read_pem(value: &str) -> Result<String> {
+    if value.trim_start().starts_with("-----BEGIN") {
+        return Ok(value.to_string());
+    }
+    fs::read_to_string(value.trim()).with_context(|| format!("failed to read pem file {}", value))
+}
+
+fn config_arg(mut args: impl Iterator<Item = String>) -> Option<PathBuf> {
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
+/// The files that exist, in order of precedence, a required one must exist
+fn read_layers(sources: &[(PathBuf, bool)]) -> Result<Vec<(PathBuf, Value)>> {
+    let mut layers = Vec::new();
+    for (path, required) in sources {
+        if !required && !path.exists() {
+            continue;
+        }
+        let layer = load_file(path)?;
+        info!("Loaded config from {}", path.display());
+        layers.push((path.clone(), layer));
+    }
+    Ok(layers)
+}
+
+fn merge_layers(layers: Vec<(PathBuf, Value)>) -> Result<Value> {
+    let mut merged: Option<Value> = None;
+    for (_, layer) in layers {
+        merged = Some(match merged {
+            Some(mut base) => {
+                merge(&mut base, layer);
+                base
+            }
+            None => layer,
+        });
+    }
+
+    merged.context("No config file found")
+}
+
+/// Secrets must come from env or a file other than the checked-in `default`, whose
+/// keys are public. A production file that leaves one out would otherwise run with
+/// the development key, so that fails. With only the default file loaded it's a
+/// local run, which is allowed with a warning
+fn check_secrets(
+    layers: &[(PathBuf, Value)],
+    default: &Path,
+    prefix: &str,
+    secrets: &[&str],
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<()> {
+    let vars: Vec<String> = vars.map(|(k, _)| k).collect();
+    let deployed = layers.iter().any(|(path, _)| path != default);
+    for secret in secrets {
+        let keys: Vec<&str> = secret.split('.').collect();
+        let var = format!("{}{}", prefix, keys.join("__").to_uppercase());
+        if vars.contains(&var) {
+            continue;
+        }
+        // the last layer setting it wins
+        let origin = layers
+            .iter()
+            .rev()
+            .find(|(_, value)| get_path(value, &keys).is_some())
+            .map(|(path, _)| path);
+        if origin.map(PathBuf::as_path) != Some(default) {
+            continue;
+        }
+        if deployed {
+            anyhow::bail!(
+                "{} comes from the development defaults in {}, set it in the config file or env {}",
+                secret,
+                default.display(),
+                var
+            );
+        }
+        warn!(
+            "!!! {} comes from {}, which is public, use it for local development only !!!",
+            secret,
+            default.display()
+        );
+    }
+    Ok(())
+}
+
+fn get_path<'a>(value: &'a Value, keys: &[&str]) -> Option<&'a Value> {
+    keys.iter().try_fold(value, |value, key| value.get(*key))
+}
+
+fn load_file(path: &Path) -> Result<Value> {
+    let file = fs::File::open(path)
+        .with_context(|| format!("failed to open config {}", path.display()))?;
+    let value = serde_yaml::from_reader(file)
+        .with_context(|| format!("failed to parse config {}", path.display()))?;
+    Ok(value)
+}
+
+/// Deep merge `layer` into `base`, mappings are merged key by key, anything else is replaced
+fn merge(base: &mut Value, layer: Value) {
+    match (base, layer) {
+        (Value::Mapping(base), Value::Mapping(layer)) => {
+            for (key, value) in layer {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, layer) => *base = layer,
+    }
+}
+
+fn apply_env_overrides(
+    value: &mut Value,
+    prefix: &str,
+    vars: impl Iterator<Item = (String, String)>,
+) {
+    for (key, raw) in vars {
+        let Some(path) = key.strip_prefix(prefix) else {
+            continue;
+        };
+        // top level keys are sections, so a field always has a `__` in its path
+        if !path.contains("__") {
+            continue;
+        }
+        let keys: Vec<String> = path.split("__").map(|k| k.to_lowercase()).collect();
+        set_path(value, &keys, raw);
+    }
+}
+
+fn set_path(value: &mut Value, keys: &[String], raw: String) {
+    let Some((last, parents)) = keys.split_last() else {
+        return;
+    };
+
+    let mut current = value;
+    for key in parents {
+        current = as_mapping(current)
+            .entry(Value::String(key.clone()))
+            .or_insert_with(|| Value::Mapping(Mapping::new()));
+    }
+
+    let map = as_mapping(current);
+    let key = Value::String(last.clone());
+    // keep strings as strings, so a numeric password is not turned into a number
+    let new = match map.get(&key) {
+        Some(Value::String(_)) => Value::String(raw),
+        _ => parse_scalar(raw),
+    };
+    map.insert(key, new);
+}
+
+fn as_mapping(value: &mut Value) -> &mut Mapping {
+    if !value.is_mapping() {
+        *value = Value::Mapping(Mapping::new());
+    }
+    match value {
+        Value::Mapping(map) => map,
+        _ => unreachable!("value was just set to a mapping"),
+    }
+}
+
+fn parse_scalar(raw: String) -> Value {
+    if let Ok(v) = raw.parse::<i64>() {
+        return Value::Number(v.into());
+    }
+    if let Ok(v) = raw.parse::<f64>() {
+        return Value::Number(v.into());
+    }
+    match raw.as_str() {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ => Value::String(raw),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    struct TestConfig {
+        server: TestServerConfig,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct TestServerConfig {
+        port: u16,
+        db_url: String,
+        password: String,
+        debug: Option<bool>,
+    }
+
+    #[test]
+    fn config_arg_should_work() {
+        let args = ["chat_server", "--config", "/etc/chat/prod.yaml"];
+        let path = config_arg(args.iter().map(|s| s.to_string()));
+        assert_eq!(path, Some(PathBuf::from("/etc/chat/prod.yaml")));
+
+        let args = ["chat_server", "--config=prod.yaml"];
+        let path = config_arg(args.iter().map(|s| s.to_string()));
+        assert_eq!(path, Some(PathBuf::from("prod.yaml")));
+
+        assert_eq!(config_arg(["chat_server".to_string()].into_iter()), None);
+    }
+
+    #[test]
+    fn load_layers_should_merge_partial_files() -> Result<()> {
+        let dir = env::temp_dir().join(format!("chat_core_config_{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let system = dir.join("system.yaml");
+        let local = dir.join("local.yaml");
+        let explicit = dir.join("explicit.yaml");
+        fs::write(
+            &system,
+            "server:\n  port: 6688\n  db_url: postgres://system/chat\n  password: system\n",
+        )?;
+        fs::write(&local, "server:\n  db_url: postgres://local/chat\n")?;
+        fs::write(&explicit, "server:\n  port: 7788\n")?;
+
+        let sources = [
+            (system, false),
+            (dir.join("missing.yaml"), false),
+            (local, false),
+            (explicit, true),
+        ];
+        let config: TestConfig = serde_yaml::from_value(merge_layers(read_layers(&sources)?)?)?;
+        assert_eq!(config.server.port, 7788);
+        assert_eq!(config.server.db_url, "postgres://local/chat");
+        assert_eq!(config.server.password, "system");
+
+        let sources = [(dir.join("missing.yaml"), true)];
+        assert!(read_layers(&sources).is_err());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn secrets_should_not_be_inherited_from_the_default_file() -> Result<()> {
+        let default = PathBuf::from("app.yaml");
+        let dev = (
+            default.clone(),
+            serde_yaml::from_str("auth:\n  sk: dev-key\nserver:\n  port: 6688\n")?,
+        );
+        let partial = (
+            PathBuf::from("/etc/chat/prod.yaml"),
+            serde_yaml::from_str("server:\n  port: 7788\n")?,
+        );
+        let full = (
+            PathBuf::from("/etc/chat/prod.yaml"),
+            serde_yaml::from_str("auth:\n  sk: prod-key\n")?,
+        );
+        let check = |layers: &[(PathBuf, Value)], vars: &[&str]| {
+            let vars = vars.iter().map(|k| (k.to_string(), String::new()));
+            check_secrets(layers, &default, "CHAT_", &["auth.sk"], vars)
+        };
+
+        // a local run, only warns
+        check(std::slice::from_ref(&dev), &[])?;
+        assert!(check(&[dev.clone(), partial.clone()], &[]).is_err());
+        check(&[dev.clone(), partial.clone()], &["CHAT_AUTH__SK"])?;
+        check(&[dev, full], &[])?;
+        Ok(())
+    }
+
+    #[test]
+    fn read_pem_should_accept_inline_or_path() -> Result<()> {
+        let pem = include_str!("../../fixtures/decoding.pem");
+        assert_eq!(read_pem(pem)?, pem);
+
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/decoding.pem");
+        assert_eq!(read_pem(path)?, pem);
+        assert!(read_pem("/no/such/key.pem").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn env_overrides_should_work() -> Result<()> {
+        let mut value: Value = serde_yaml::from_str(
+            r#"
+            server:
+              port: 6688
+              db_url: postgres://localhost:5432/chat
+              password: "secret"
+            "#,
+        )?;
+        let vars = [
+            ("CHAT_SERVER__PORT", "7788"),
+            ("CHAT_SERVER__DB_URL", "postgres://db:5432/chat"),
+            ("CHAT_SERVER__PASSWORD", "123456"),
+            ("CHAT_SERVER__DEBUG", "true"),
+            ("CHAT_CONFIG", "/tmp/app.yaml"),
+            ("NOTIFY_SERVER__PORT", "1"),
+        ];
+        apply_env_overrides(
+            &mut value,
+            "CHAT_",
+            vars.iter().map(|(k, v)| (k.to_string(), v.to_string())),
+        );
+
+        let config: TestConfig = serde_yaml::from_value(value)?;
+        assert_eq!(config.server.port, 7788);
+        assert_eq!(config.server.db_url, "postgres://db:5432/chat");
+        assert_eq!(config.server.password, "123456");
+        assert_eq!(config.server.debug, Some(true));
+        Ok(())
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+
+use jwt_simple::{
+    prelude::*,
+    reexports::ct_codecs::{Base64UrlSafeNoPadding, Decoder, Encoder},
+};
+
+use crate::CoreError;
+
+/// Access tokens are short lived, clients renew them with their refresh token
+const JWT_DURATION: u64 = 60 * 15;
+const JWT_ISSUER: &str = "chat_server";
+const JWT_AUDIENCE: &str = "chat_client";
+
+/// Custom claims of an access token: the user, its workspace and the session it was
+/// issued for. The user itself is looked up on each request, so tokens don't carry
+/// stale profiles
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserClaims {
+    pub uid: i64,
+    pub wid: i64,
+    pub sid: i64,
+}
+
+/// A verification key in the config, `pk` is an inline PEM or a path to it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublicKeyConfig {
+    pub kid: String,
+    pub pk: String,
+}
+
+/// JSON Web Key Set, as served on `/.well-known/jwks.json`
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+/// An Ed25519 public key as a JWK (RFC 8037)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Jwk {
+    pub kty: String,
+    pub crv: String,
+    pub alg: String,
+    #[serde(rename = "use")]
+    pub use_: String,
+    pub kid: String,
+    pub x: String,
+}
+
+/// The current signing key, its id is put in the `kid` header of every token
+pub struct EncodingKey {
+    kid: String,
+    key: Ed25519KeyPair,
+}
+
+/// All the keys tokens are accepted from, by key id
+#[derive(Clone, Default)]
+pub struct DecodingKey(HashMap<String, Ed25519PublicKey>);
+
+impl EncodingKey {
+    pub fn load(kid: &str, pem: &str) -> Result<Self, CoreError> {
+        let key = Ed25519KeyPair::from_pem(pem)?.with_key_id(kid);
+        Ok(Self {
+            kid: kid.to_string(),
+            key,
+        })
+    }
+
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    /// The public half of the key, to verify the tokens it signs
+    pub fn decoding_key(&self) -> DecodingKey {
+        let mut dk = DecodingKey::default();
+        dk.insert(&self.kid, self.key.public_key());
+        dk
+    }
+
+    pub fn sign(&self, claims: UserClaims) -> Result<String, CoreError> {
+        let claims = Claims::with_custom_claims(claims, Duration::from_secs(JWT_DURATION));
+        let claims = claims.with_issuer(JWT_ISSUER).with_audience(JWT_AUDIENCE);
+        let token = self.key.sign(claims)?;
+        Ok(token)
+    }
+}
+
+impl DecodingKey {
+    /// Load the keys from the config, the PEMs must already be read
+    pub fn load(keys: &[PublicKeyConfig]) -> Result<Self, CoreError> {
+        let mut dk = Self::default();
+        for key in keys {
+            dk.insert(&key.kid, Ed25519PublicKey::from_pem(&key.pk)?);
+        }
+        Ok(dk)
+    }
+
+    pub fn from_jwks(jwks: &Jwks) -> Result<Self, CoreError> {
+        let mut dk = Self::default();
+        // skip keys of other types, a key set may be shared with other algorithms
+        for jwk in jwks
+            .keys
+            .iter()
+            .filter(|k| k.kty == "OKP" && k.crv == "Ed25519")
+        {
+            let raw = Base64UrlSafeNoPadding::decode_to_vec(&jwk.x, None)
+                .map_err(|e| CoreError::InvalidToken(format!("invalid jwk {}: {}", jwk.kid, e)))?;
+            dk.insert(&jwk.kid, Ed25519PublicKey::from_bytes(&raw)?);
+        }
+        Ok(dk)
+    }
+
+    pub fn jwks(&self) -> Jwks {
+        let mut keys: Vec<Jwk> = self
+            .0
+            .iter()
+            .map(|(kid, key)| Jwk {
+                kty: "OKP".to_string(),
+                crv: "Ed25519".to_string(),
+                alg: "EdDSA".to_string(),
+                use_: "sig".to_string(),
+                kid: kid.clone(),
+                x: Base64UrlSafeNoPadding::encode_to_string(key.to_bytes())
+                    .expect("base64 encoding of a key can't fail"),
+            })
+            .collect();
+        keys.sort_by(|a, b| a.kid.cmp(&b.kid));
+        Jwks { keys }
+    }
+
+    pub fn contains(&self, kid: &str) -> bool {
+        self.0.contains_key(kid)
+    }
+
+    pub fn extend(&mut self, other: DecodingKey) {
+        self.0.extend(other.0);
+    }
+
+    /// Verify a token with the key named by its `kid` header
+    pub fn verify(&self, token: &str) -> Result<UserClaims, CoreError> {
+        let kid = token_kid(token)?;
+        let key = self
+            .0
+            .get(&kid)
+            .ok_or_else(|| CoreError::UnknownKey(kid.clone()))?;
+
+        let options = VerificationOptions {
+            allowed_issuers: Some(HashSet::from_strings(&[JWT_ISSUER])),
+            allowed_audiences: Some(HashSet::from_strings(&[JWT_AUDIENCE])),
+            required_key_id: Some(kid),
+            ..Default::default()
+        };
+
+        let claims = key.verify_token::<UserClaims>(token, Some(options))?;
+
+        Ok(claims.custom)
+    }
+
+    fn insert(&mut self, kid: &str, key: Ed25519PublicKey) {
+        self.0.insert(kid.to_string(), key.with_key_id(kid));
+    }
+}
+
+/// The `kid` header of a token, without verifying it
+pub fn token_kid(token: &str) -> Result<String, CoreError> {
+    let metadata = Token::decode_metadata(token)?;
+    metadata
+        .key_id()
+        .map(|kid| kid.to_string())
+        .ok_or_else(|| CoreError::InvalidToken("token has no kid".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    fn claims(uid: i64, sid: i64) -> UserClaims {
+        UserClaims { uid, wid: 1, sid }
+    }
+
+    #[test]
+    fn test_jwt() -> Result<()> {
+        let encoding_pem = include_str!("../../fixtures/encoding.pem");
+        let decoding_pem = include_str!("../../fixtures/decoding.pem");
+
+        let ek = EncodingKey::load("dev", encoding_pem)?;
+        let dk = DecodingKey::load(&[PublicKeyConfig {
+            kid: "dev".to_string(),
+            pk: decoding_pem.to_string(),
+        }])?;
+
+        let token = ek.sign(claims(1, 42))?;
+        assert_eq!(token_kid(&token)?, "dev");
+
+        let claims = dk.verify(&token)?;
+        assert_eq!(claims.uid, 1);
+        assert_eq!(claims.sid, 42);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rotated_keys_should_be_picked_by_kid() -> Result<()> {
+        let old = EncodingKey::load("old", include_str!("../../fixtures/encoding.pem"))?;
+        let new = EncodingKey {
+            kid: "new".to_string(),
+            key: Ed25519KeyPair::generate().with_key_id("new"),
+        };
+        let old_token = old.sign(claims(1, 1))?;
+        let new_token = new.sign(claims(1, 1))?;
+
+        let mut dk = new.decoding_key();
+        assert!(matches!(dk.verify(&old_token), Err(CoreError::UnknownKey(kid)) if kid == "old"));
+
+        dk.extend(old.decoding_key());
+        assert_eq!(dk.verify(&old_token)?.uid, 1);
+        assert_eq!(dk.verify(&new_token)?.uid, 1);
+
+        // a token signed by another key under a known kid is rejected
+        let forged = EncodingKey {
+            kid: "old".to_string(),
+            key: Ed25519KeyPair::generate().with_key_id("old"),
+        };
+        assert!(dk.verify(&forged.sign(claims(2, 1))?).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn jwks_should_round_trip() -> Result<()> {
+        let ek = EncodingKey::load("dev", include_str!("../../fixtures/encoding.pem"))?;
+        let jwks = ek.decoding_key().jwks();
+        assert_eq!(jwks.keys.len(), 1);
+        assert_eq!(jwks.keys[0].kid, "dev");
+        assert_eq!(jwks.keys[0].crv, "Ed25519");
+
+        let json = serde_json::to_string(&jwks)?;
+        assert!(json.contains(r#""use":"sig""#));
+        let dk = DecodingKey::from_jwks(&serde_json::from_str(&json)?)?;
+        let token = ek.sign(claims(1, 1))?;
+        assert_eq!(dk.verify(&token)?.sid, 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,8 @@
+mod config;
+mod jwt;
+mod rate_limiter;
+mod user_cache;
+pub use config::{load_config, read_pem};
+pub use jwt::{token_kid, DecodingKey, EncodingKey, Jwk, Jwks, PublicKeyConfig, UserClaims};
+pub use rate_limiter::RateLimiter;
+pub use user_cache::UserCache;
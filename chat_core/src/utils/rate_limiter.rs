@@ -0,0 +1,57 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// entries are pruned once there are more than this
+const RATE_LIMITER_PRUNE_AT: usize = 10_000;
+
+/// Lets through at most one event per key and interval, e.g. per user.
+/// Kept in memory, so every instance of a server limits on its own
+pub struct RateLimiter {
+    interval: Duration,
+    last: Mutex<HashMap<i64, Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether an event for the key may go now, if so it counts as the latest
+    pub fn check(&self, key: i64) -> bool {
+        let now = Instant::now();
+        let mut last = self.last.lock().expect("rate limiter lock poisoned");
+        if let Some(at) = last.get(&key) {
+            if now.duration_since(*at) < self.interval {
+                return false;
+            }
+        }
+        if last.len() >= RATE_LIMITER_PRUNE_AT {
+            last.retain(|_, at| now.duration_since(*at) < self.interval);
+        }
+        last.insert(key, now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_should_allow_one_event_per_interval() {
+        let limiter = RateLimiter::new(Duration::from_millis(50));
+        assert!(limiter.check(1));
+        assert!(!limiter.check(1));
+        // keys are limited on their own
+        assert!(limiter.check(2));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(limiter.check(1));
+    }
+}
@@ -0,0 +1,115 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use sqlx::PgPool;
+
+use crate::User;
+
+const USER_CACHE_TTL: Duration = Duration::from_secs(30);
+const USER_CACHE_CAPACITY: usize = 10_000;
+
+/// Users by id, kept for a short time since every authenticated request needs its user.
+/// A changed profile shows up once the entry expires, or right away if invalidated
+pub struct UserCache {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<HashMap<i64, (Instant, User)>>,
+}
+
+impl Default for UserCache {
+    fn default() -> Self {
+        Self::new(USER_CACHE_TTL, USER_CACHE_CAPACITY)
+    }
+}
+
+impl UserCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get a user from the cache, or from the database if missing or expired
+    pub async fn get(&self, pool: &PgPool, id: i64) -> Result<Option<User>, sqlx::Error> {
+        if let Some(user) = self.cached(id) {
+            return Ok(Some(user));
+        }
+
+        let user: Option<User> =
+            sqlx::query_as(r#"SELECT id,ws_id,fullname,email,created_at FROM users WHERE id = $1"#)
+                .bind(id)
+                .fetch_optional(pool)
+                .await?;
+        if let Some(user) = &user {
+            self.insert(user.clone());
+        }
+
+        Ok(user)
+    }
+
+    pub fn invalidate(&self, id: i64) {
+        self.lock().remove(&id);
+    }
+
+    fn cached(&self, id: i64) -> Option<User> {
+        let entries = self.lock();
+        match entries.get(&id) {
+            Some((at, user)) if at.elapsed() < self.ttl => Some(user.clone()),
+            _ => None,
+        }
+    }
+
+    fn insert(&self, user: User) {
+        let mut entries = self.lock();
+        if entries.len() >= self.capacity {
+            entries.retain(|_, (at, _)| at.elapsed() < self.ttl);
+        }
+        if entries.len() >= self.capacity {
+            // all entries are fresh, drop the oldest to make room
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (at, _))| *at)
+                .map(|(id, _)| *id);
+            if let Some(id) = oldest {
+                entries.remove(&id);
+            }
+        }
+        entries.insert(user.id, (Instant::now(), user));
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<i64, (Instant, User)>> {
+        self.entries.lock().expect("user cache lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_cache_should_expire_and_evict() {
+        let cache = UserCache::new(Duration::from_millis(50), 2);
+        cache.insert(User::new(1, "Alice", "alice@acme.org"));
+        cache.insert(User::new(2, "Bob", "bob@acme.org"));
+        assert_eq!(
+            cache.cached(1).map(|u| u.fullname),
+            Some("Alice".to_string())
+        );
+
+        // full, the oldest entry makes room
+        cache.insert(User::new(3, "Charlie", "charlie@acme.org"));
+        assert!(cache.cached(1).is_none());
+        assert!(cache.cached(3).is_some());
+
+        cache.invalidate(3);
+        assert!(cache.cached(3).is_none());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(cache.cached(2).is_none());
+    }
+}
@@ -1,30 +1,29 @@
-use std::{env, fs::File};
+use std::path::PathBuf;
 
 use anyhow::Result;
+use chat_core::{load_config, read_pem, PublicKeyConfig};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AppConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub upload: UploadConfig,
 }
 
 impl AppConfig {
     pub fn load() -> Result<Self> {
-        // Reade from /etc/config/app.yaml or ./app.yaml or fro env CHAT_CONFIG
-
-        let ret = match (
-            File::open("app.yaml"),
-            File::open("/etc/config/app.yaml"),
-            env::var("CHAT_CONFIG"),
-        ) {
-            (Ok(file), _, _) => serde_yaml::from_reader(file),
-            (_, Ok(file), _) => serde_yaml::from_reader(file),
-            (_, _, Ok(path)) => serde_yaml::from_reader(File::open(path)?),
-            _ => anyhow::bail!("No config file found"),
-        };
-
-        Ok(ret?)
+        // Merge --config > env CHAT_CONFIG > ./app.yaml > /etc/config/app.yaml,
+        // every field can be overridden by env, e.g. CHAT_SERVER__PORT. The signing
+        // key of ./app.yaml is public, it's refused once a production file is used
+        let mut config: Self = load_config("app.yaml", "CHAT_", &["auth.sk"])?;
+        // keys are either inline PEM or a path to the PEM file
+        config.auth.sk = read_pem(&config.auth.sk)?;
+        for key in config.auth.keys.iter_mut() {
+            key.pk = read_pem(&key.pk)?;
+        }
+        Ok(config)
     }
 }
 
@@ -36,6 +35,69 @@ pub struct ServerConfig {
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AuthConfig {
+    /// id of the signing key, put in the `kid` header of the tokens
+    pub kid: String,
     pub sk: String,
-    pub pk: String,
+    /// public keys of retired signing keys, tokens they signed are still accepted
+    #[serde(default)]
+    pub keys: Vec<PublicKeyConfig>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct UploadConfig {
+    pub storage: StorageConfig,
+    /// max size of a single file in bytes
+    pub max_size: usize,
+    /// accepted MIME types, as sent by the client
+    pub allowed_types: Vec<String>,
+    /// how long a presigned download url is valid, in seconds
+    pub presign_expires: u64,
+    /// side of the square image thumbnails fit in, in pixels
+    pub thumbnail_size: u32,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            storage: StorageConfig::Local {
+                base_dir: PathBuf::from("/tmp/chat_server"),
+            },
+            max_size: 10 * 1024 * 1024,
+            allowed_types: ["image/png", "image/jpeg", "image/gif", "image/webp"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            presign_expires: 300,
+            thumbnail_size: 256,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum StorageConfig {
+    /// a directory on this server, files are served by chat_server
+    Local { base_dir: PathBuf },
+    /// an S3 compatible bucket, clients download through presigned urls
+    S3(S3Config),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct S3Config {
+    /// e.g. https://s3.amazonaws.com or http://localhost:9000 for MinIO, a path
+    /// like http://proxy/s3 is kept in front of the bucket or key
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// put the bucket in the path instead of the host name, as MinIO expects
+    #[serde(default)]
+    pub path_style: bool,
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
 }
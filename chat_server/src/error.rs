@@ -1,7 +1,8 @@
 use axum::http::StatusCode;
 use axum::response::Json;
 use axum::response::{IntoResponse, Response};
-use jwt_simple::reexports::serde_json::json;
+use chat_core::CoreError;
+use serde_json::json;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -13,11 +14,46 @@ pub enum AppError {
     PasswordHashError(#[from] argon2::password_hash::Error),
     #[error("jwt error: {0}")]
     JWTError(#[from] jwt_simple::Error),
+    #[error("{0}")]
+    CoreError(#[from] CoreError),
+
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("multipart error: {0}")]
+    MultipartError(#[from] axum::extract::multipart::MultipartError),
+    #[error("{0}")]
+    ImageError(#[from] crate::image::ImageError),
 
     #[error("http header parse error:{0}")]
     HttpHeaderError(#[from] axum::http::header::InvalidHeaderValue),
     #[error("email already exists:{0}")]
     EmailAlreadyExists(String),
+    #[error("chat already exists:{0}")]
+    ChatAlreadyExists(String),
+    #[error("create chat error:{0}")]
+    CreateChatError(String),
+    #[error("update chat error:{0}")]
+    UpdateChatError(String),
+    #[error("create message error:{0}")]
+    CreateMessageError(String),
+    #[error("update message error:{0}")]
+    UpdateMessageError(String),
+    #[error("reaction error:{0}")]
+    ReactionError(String),
+    #[error("storage error:{0}")]
+    StorageError(String),
+    #[error("upload error:{0}")]
+    UploadError(String),
+    #[error("file too large, max {0} bytes")]
+    FileTooLarge(usize),
+    #[error("unsupported file type:{0}")]
+    UnsupportedFileType(String),
+    #[error("permission denied:{0}")]
+    PermissionDenied(String),
+    #[error("not found:{0}")]
+    NotFound(String),
+    #[error("too many requests:{0}")]
+    TooManyRequests(String),
 }
 
 impl IntoResponse for AppError {
@@ -26,8 +62,25 @@ impl IntoResponse for AppError {
             AppError::SqlxError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::PasswordHashError(_) => StatusCode::UNPROCESSABLE_ENTITY,
             AppError::JWTError(_) => StatusCode::FORBIDDEN,
+            AppError::CoreError(ref e) => e.status_code(),
+            AppError::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::MultipartError(ref e) => e.status(),
+            AppError::ImageError(_) => StatusCode::BAD_REQUEST,
             AppError::HttpHeaderError(_) => StatusCode::UNPROCESSABLE_ENTITY,
             AppError::EmailAlreadyExists(_) => StatusCode::CONFLICT,
+            AppError::ChatAlreadyExists(_) => StatusCode::CONFLICT,
+            AppError::CreateChatError(_) => StatusCode::BAD_REQUEST,
+            AppError::UpdateChatError(_) => StatusCode::BAD_REQUEST,
+            AppError::CreateMessageError(_) => StatusCode::BAD_REQUEST,
+            AppError::UpdateMessageError(_) => StatusCode::BAD_REQUEST,
+            AppError::ReactionError(_) => StatusCode::BAD_REQUEST,
+            AppError::StorageError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::UploadError(_) => StatusCode::BAD_REQUEST,
+            AppError::FileTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::UnsupportedFileType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::PermissionDenied(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
         };
 
         (status, Json(json!({"error":self.to_string()}))).into_response()
@@ -1,27 +1,17 @@
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
-use serde::{Deserialize, Serialize};
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
+use chat_core::{Jwks, User};
 
 use crate::{
-    models::{CreateUser, SigninUser},
-    AppError, AppState, User,
+    models::{AuthOutput, ChangePassword, CreateUser, RefreshInput, SigninUser},
+    AppError, AppState,
 };
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct AuthOutput {
-    token: String,
-}
-
 pub(crate) async fn signup_handler(
     State(state): State<AppState>,
     Json(input): Json<CreateUser>,
 ) -> Result<impl IntoResponse, AppError> {
-    let user = User::create(&input, &state.pool).await?;
-    let token = state.ek.sign(user)?;
-    // let mut header = HeaderMap::new();
-    // header.insert("X-Token", token.parse()?);
-    // Ok((StatusCode::CREATED, header))
-
-    let output = AuthOutput { token };
+    let user = state.create_user(&input).await?;
+    let output = state.create_session(&user).await?;
 
     Ok((StatusCode::CREATED, Json(output)).into_response())
 }
@@ -30,11 +20,10 @@ pub(crate) async fn signin_handler(
     State(state): State<AppState>,
     Json(input): Json<SigninUser>,
 ) -> Result<impl IntoResponse, AppError> {
-    let user = User::verify(&input, &state.pool).await?;
+    let user = state.verify_user(&input).await?;
     match user {
         Some(user) => {
-            let token = state.ek.sign(user)?;
-            let output = AuthOutput { token };
+            let output = state.create_session(&user).await?;
 
             Ok((StatusCode::OK, Json(output)).into_response())
         }
@@ -42,6 +31,42 @@ pub(crate) async fn signin_handler(
     }
 }
 
+/// Rotate the refresh token and issue a new access token
+pub(crate) async fn refresh_handler(
+    State(state): State<AppState>,
+    Json(input): Json<RefreshInput>,
+) -> Result<Json<AuthOutput>, AppError> {
+    let output = state.refresh_session(&input.refresh_token).await?;
+
+    Ok(Json(output))
+}
+
+/// Revoke the session, takes the refresh token so that it works with an expired access token
+pub(crate) async fn signout_handler(
+    State(state): State<AppState>,
+    Json(input): Json<RefreshInput>,
+) -> Result<impl IntoResponse, AppError> {
+    state.signout(&input.refresh_token).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Change the password, every session of the user is revoked and new tokens are issued
+pub(crate) async fn change_password_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<ChangePassword>,
+) -> Result<Json<AuthOutput>, AppError> {
+    let output = state.change_password(&user, &input).await?;
+
+    Ok(Json(output))
+}
+
+/// Public keys the tokens can be verified with, for the other services
+pub(crate) async fn jwks_handler(State(state): State<AppState>) -> Json<Jwks> {
+    Json(state.dk.jwks())
+}
+
 #[cfg(test)]
 mod tests {
     use crate::AppConfig;
@@ -56,7 +81,7 @@ mod tests {
 
         let (_tdb, state) = AppState::new_for_test(config).await?;
 
-        let input = CreateUser::new("Manonloki", "manonloki@gmail.com", "loki1988");
+        let input = CreateUser::new("acme", "Manonloki", "manonloki@gmail.com", "loki1988");
         let response = signup_handler(State(state), Json(input))
             .await?
             .into_response();
@@ -74,8 +99,8 @@ mod tests {
 
         let (_tdb, state) = AppState::new_for_test(config).await?;
 
-        let input = CreateUser::new("Alice", "alice@acme.org", "alice1988");
-        User::create(&input, &state.pool).await?;
+        let input = CreateUser::new("acme", "Alice", "alice@acme.org", "alice1988");
+        state.create_user(&input).await?;
 
         let input = SigninUser::new("alice@acme.org", "alice1988");
         let response = signin_handler(State(state), Json(input))
@@ -90,4 +115,47 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn refresh_and_signout_should_work() -> Result<()> {
+        let config = AppConfig::load()?;
+
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+
+        let input = CreateUser::new("acme", "Alice", "alice@acme.org", "alice1988");
+        let user = state.create_user(&input).await?;
+        let output = state.create_session(&user).await?;
+
+        let input = RefreshInput {
+            refresh_token: output.refresh_token,
+        };
+        let response = refresh_handler(State(state.clone()), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = response.into_body().collect().await?.to_bytes();
+        let output: AuthOutput = serde_json::from_slice(&bytes)?;
+
+        let input = RefreshInput {
+            refresh_token: output.refresh_token,
+        };
+        let response = signout_handler(State(state), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn jwks_should_contain_signing_key() -> Result<()> {
+        let config = AppConfig::load()?;
+        let (_tdb, state) = AppState::new_for_test(config).await?;
+
+        let Json(jwks) = jwks_handler(State(state.clone())).await;
+        assert_eq!(jwks.keys.len(), 1);
+        assert_eq!(jwks.keys[0].kid, state.ek.kid());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,30 @@
+use axum::{
+    extract::{Path, Query, State},
+    response::IntoResponse,
+    Extension, Json,
+};
+
+use chat_core::User;
+
+use crate::{models::ListChannels, AppError, AppState};
+
+/// Browse and search the public channels of the caller's workspace
+pub(crate) async fn list_channels_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListChannels>,
+) -> Result<impl IntoResponse, AppError> {
+    let channels = state
+        .fetch_public_channels(&input, user.id, user.ws_id)
+        .await?;
+    Ok(Json(channels))
+}
+
+pub(crate) async fn join_channel_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state.join_channel(id, user.id, user.ws_id).await?;
+    Ok(Json(chat))
+}
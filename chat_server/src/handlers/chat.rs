@@ -1,20 +1,81 @@
-use axum::{response::IntoResponse, Extension};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
 
-use crate::User;
+use chat_core::User;
 
-pub(crate) async fn list_chat_handler(Extension(user): Extension<User>) -> impl IntoResponse {
-    tracing::info!("User: {:?}", user);
-    "chat"
+use crate::{
+    models::{CreateChat, MarkChatRead, UpdateChat},
+    AppError, AppState,
+};
+
+pub(crate) async fn list_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let chats = state.fetch_chat_summaries(user.id, user.ws_id).await?;
+    Ok((StatusCode::OK, Json(chats)))
+}
+
+pub(crate) async fn create_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateChat>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state.create_chat(&input, user.id, user.ws_id).await?;
+    Ok((StatusCode::CREATED, Json(chat)))
+}
+
+pub(crate) async fn get_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    match state.get_chat_by_id(id, user.id, user.ws_id).await? {
+        Some(chat) => Ok(Json(chat)),
+        None => Err(AppError::NotFound(format!("chat id {}", id))),
+    }
+}
+
+pub(crate) async fn update_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(input): Json<UpdateChat>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state.update_chat(id, &input, user.id, user.ws_id).await?;
+    Ok((StatusCode::OK, Json(chat)))
 }
 
-pub(crate) async fn create_chat_handler() -> impl IntoResponse {
-    "create chat"
+pub(crate) async fn delete_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.delete_chat(id, user.id, user.ws_id).await?;
+    Ok(StatusCode::NO_CONTENT)
 }
 
-pub(crate) async fn update_chat_handler() -> impl IntoResponse {
-    "update chat"
+pub(crate) async fn mark_chat_read_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(input): Json<MarkChatRead>,
+) -> Result<impl IntoResponse, AppError> {
+    let read = state
+        .mark_chat_read(&input, id, user.id, user.ws_id)
+        .await?;
+    Ok((StatusCode::OK, Json(read)))
 }
 
-pub(crate) async fn delete_chat_handler() -> impl IntoResponse {
-    "delete chat"
+pub(crate) async fn send_typing_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.send_typing(id, user.id, user.ws_id).await?;
+    Ok(StatusCode::NO_CONTENT)
 }
@@ -0,0 +1,58 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+
+use chat_core::User;
+
+use crate::{
+    models::{AddChatMembers, UpdateChatMember},
+    AppError, AppState,
+};
+
+pub(crate) async fn list_chat_members_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    let members = state.fetch_chat_members(id, user.id, user.ws_id).await?;
+    Ok(Json(members))
+}
+
+pub(crate) async fn add_chat_members_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(input): Json<AddChatMembers>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state
+        .add_chat_members(id, &input, user.id, user.ws_id)
+        .await?;
+    Ok((StatusCode::OK, Json(chat)))
+}
+
+pub(crate) async fn update_chat_member_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, member_id)): Path<(i64, i64)>,
+    Json(input): Json<UpdateChatMember>,
+) -> Result<impl IntoResponse, AppError> {
+    let member = state
+        .update_chat_member(id, member_id, &input, user.id, user.ws_id)
+        .await?;
+    Ok((StatusCode::OK, Json(member)))
+}
+
+/// Remove a member, or leave the chat when the member is the caller
+pub(crate) async fn remove_chat_member_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, member_id)): Path<(i64, i64)>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .remove_chat_member(id, member_id, user.id, user.ws_id)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,76 @@
+use axum::{
+    extract::{Multipart, Path, State},
+    http::{header, HeaderValue},
+    response::{IntoResponse, Redirect, Response},
+    Extension, Json,
+};
+
+use chat_core::User;
+
+use crate::{models::FileContent, AppError, AppState};
+
+/// Store each file of a multipart upload, returns their urls in order
+pub(crate) async fn upload_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, AppError> {
+    let max_size = state.config.upload.max_size;
+    let mut urls = Vec::new();
+    while let Some(mut field) = multipart.next_field().await? {
+        let Some(filename) = field.file_name().map(|s| s.to_string()) else {
+            continue;
+        };
+        let content_type = field.content_type().unwrap_or_default().to_string();
+
+        // stop reading as soon as the file is too large
+        let mut data = Vec::new();
+        while let Some(chunk) = field.chunk().await? {
+            if data.len() + chunk.len() > max_size {
+                return Err(AppError::FileTooLarge(max_size));
+            }
+            data.extend_from_slice(&chunk);
+        }
+
+        let url = state
+            .save_file(user.ws_id, user.id, &filename, &content_type, data)
+            .await?;
+        urls.push(url);
+    }
+
+    if urls.is_empty() {
+        return Err(AppError::UploadError("no file in the request".to_string()));
+    }
+    Ok(Json(urls))
+}
+
+/// Serve a file, or send the client to the storage backend to download it
+pub(crate) async fn file_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(path): Path<String>,
+) -> Result<Response, AppError> {
+    let url = format!("/files/{}", path);
+    let (file, content) = state.read_file(&url, user.id, user.ws_id).await?;
+    let data = match content {
+        FileContent::Data(data) => data,
+        FileContent::Redirect(url) => return Ok(Redirect::temporary(&url).into_response()),
+    };
+
+    let headers = [
+        (
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(file.content_type()),
+        ),
+        (
+            header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff"),
+        ),
+        // the content of a url never changes
+        (
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("private, max-age=31536000, immutable"),
+        ),
+    ];
+    Ok((headers, data).into_response())
+}
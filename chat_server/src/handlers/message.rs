@@ -1,9 +1,102 @@
-use axum::response::IntoResponse;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
 
-pub(crate) async fn send_message_handler() -> impl IntoResponse {
-    "send message"
+use chat_core::User;
+
+use crate::{
+    models::{AddReaction, CreateMessage, ListMessages, UpdateMessage},
+    AppError, AppState,
+};
+
+pub(crate) async fn send_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(input): Json<CreateMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    let message = state
+        .create_message(&input, id, user.id, user.ws_id)
+        .await?;
+    Ok((StatusCode::CREATED, Json(message)))
+}
+
+pub(crate) async fn list_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Query(input): Query<ListMessages>,
+) -> Result<impl IntoResponse, AppError> {
+    let messages = state.list_messages(&input, id, user.id, user.ws_id).await?;
+    Ok((StatusCode::OK, Json(messages)))
+}
+
+pub(crate) async fn list_replies_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, mid)): Path<(i64, i64)>,
+    Query(input): Query<ListMessages>,
+) -> Result<impl IntoResponse, AppError> {
+    let messages = state
+        .list_replies(&input, id, mid, user.id, user.ws_id)
+        .await?;
+    Ok((StatusCode::OK, Json(messages)))
+}
+
+pub(crate) async fn update_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, mid)): Path<(i64, i64)>,
+    Json(input): Json<UpdateMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    let message = state
+        .update_message(&input, id, mid, user.id, user.ws_id)
+        .await?;
+    Ok((StatusCode::OK, Json(message)))
+}
+
+pub(crate) async fn delete_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, mid)): Path<(i64, i64)>,
+) -> Result<impl IntoResponse, AppError> {
+    state.delete_message(id, mid, user.id, user.ws_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn list_message_edits_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, mid)): Path<(i64, i64)>,
+) -> Result<impl IntoResponse, AppError> {
+    let edits = state
+        .list_message_edits(id, mid, user.id, user.ws_id)
+        .await?;
+    Ok((StatusCode::OK, Json(edits)))
+}
+
+pub(crate) async fn add_reaction_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, mid)): Path<(i64, i64)>,
+    Json(input): Json<AddReaction>,
+) -> Result<impl IntoResponse, AppError> {
+    let reactions = state
+        .add_reaction(&input, id, mid, user.id, user.ws_id)
+        .await?;
+    Ok((StatusCode::OK, Json(reactions)))
 }
 
-pub(crate) async fn list_message_handler() -> impl IntoResponse {
-    "list message"
+pub(crate) async fn remove_reaction_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, mid, emoji)): Path<(i64, i64, String)>,
+) -> Result<impl IntoResponse, AppError> {
+    let reactions = state
+        .remove_reaction(&emoji, id, mid, user.id, user.ws_id)
+        .await?;
+    Ok((StatusCode::OK, Json(reactions)))
 }
@@ -1,11 +1,19 @@
 mod auth;
+mod channel;
 mod chat;
+mod chat_member;
+mod file;
 mod message;
+mod workspace;
 
 pub(crate) use auth::*;
 use axum::response::IntoResponse;
+pub(crate) use channel::*;
 pub(crate) use chat::*;
+pub(crate) use chat_member::*;
+pub(crate) use file::*;
 pub(crate) use message::*;
+pub(crate) use workspace::*;
 
 pub(crate) async fn index_handler() -> impl IntoResponse {
     "Hello, World!"
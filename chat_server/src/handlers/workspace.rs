@@ -0,0 +1,23 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
+
+use chat_core::User;
+
+use crate::{AppError, AppState};
+
+/// Users of the caller's workspace
+pub(crate) async fn list_users_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let users = state.fetch_workspace_users(user.ws_id).await?;
+    Ok(Json(users))
+}
+
+/// Invite someone to the caller's workspace, the token goes into their signup
+pub(crate) async fn create_invite_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let invite = state.create_workspace_invite(&user).await?;
+    Ok((StatusCode::CREATED, Json(invite)))
+}
@@ -0,0 +1,595 @@
+//! Just enough image handling for uploads: find out what an image is and how
+//! large, remove location data before it is stored, and make thumbnails.
+//! Decoding is left to the `image` crate, the containers to `img-parts` and
+//! the EXIF data to `kamadak-exif`
+
+use std::io::Cursor;
+
+use ::image::{metadata::Orientation, DynamicImage, ImageDecoder, ImageReader, Limits};
+use exif::{Context, Field, In, Tag, Value};
+use img_parts::{
+    jpeg::Jpeg,
+    png::Png,
+    riff::{RiffChunk, RiffContent},
+    webp::{WebP, CHUNK_EXIF, CHUNK_VP8X, CHUNK_XMP},
+    Bytes, ImageEXIF,
+};
+use thiserror::Error;
+
+/// images with more pixels than this are not decoded, whatever their file size
+const MAX_PIXELS: u64 = 64 * 1024 * 1024;
+
+const APP1: u8 = 0xE1;
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp\0";
+/// flags of the VP8X chunk, for the metadata chunks that follow
+const WEBP_EXIF_FLAG: u8 = 0x08;
+const WEBP_XMP_FLAG: u8 = 0x04;
+/// an XMP packet in IFD0, which may hold a location as well
+const XML_PACKET: u16 = 0x02BC;
+
+#[derive(Debug, Error)]
+#[error("invalid image: {0}")]
+pub struct ImageError(String);
+
+impl ImageError {
+    fn new(msg: impl Into<String>) -> Self {
+        Self(msg.into())
+    }
+}
+
+impl From<::image::ImageError> for ImageError {
+    fn from(e: ::image::ImageError) -> Self {
+        Self(e.to_string())
+    }
+}
+
+impl From<img_parts::Error> for ImageError {
+    fn from(e: img_parts::Error) -> Self {
+        Self(e.to_string())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ImageError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+}
+
+impl ImageFormat {
+    /// Tell the format by the magic bytes, whatever the client claims it is
+    pub fn sniff(data: &[u8]) -> Option<Self> {
+        match ::image::guess_format(data).ok()? {
+            ::image::ImageFormat::Png => Some(Self::Png),
+            ::image::ImageFormat::Jpeg => Some(Self::Jpeg),
+            ::image::ImageFormat::Gif => Some(Self::Gif),
+            ::image::ImageFormat::WebP => Some(Self::Webp),
+            _ => None,
+        }
+    }
+
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        [Self::Png, Self::Jpeg, Self::Gif, Self::Webp]
+            .into_iter()
+            .find(|f| f.mime() == mime)
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+            Self::Gif => "image/gif",
+            Self::Webp => "image/webp",
+        }
+    }
+
+    pub fn ext(&self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Jpeg => "jpg",
+            Self::Gif => "gif",
+            Self::Webp => "webp",
+        }
+    }
+}
+
+impl From<ImageFormat> for ::image::ImageFormat {
+    fn from(format: ImageFormat) -> Self {
+        match format {
+            ImageFormat::Png => Self::Png,
+            ImageFormat::Jpeg => Self::Jpeg,
+            ImageFormat::Gif => Self::Gif,
+            ImageFormat::Webp => Self::WebP,
+        }
+    }
+}
+
+/// The size is the one the image is shown at, turned as its EXIF orientation says
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageInfo {
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Read the format and dimensions from the headers, without decoding
+pub fn probe(data: &[u8]) -> Result<ImageInfo> {
+    let format = ImageFormat::sniff(data).ok_or_else(|| ImageError::new("unknown format"))?;
+    if format == ImageFormat::Webp {
+        check_riff(data)?;
+    }
+    let mut decoder = reader(format, data).into_decoder()?;
+    let (width, height) = decoder.dimensions();
+    if width == 0 || height == 0 {
+        return Err(ImageError::new("empty image"));
+    }
+    let (width, height) = match orientation(&mut decoder) {
+        Orientation::Rotate90
+        | Orientation::Rotate270
+        | Orientation::Rotate90FlipH
+        | Orientation::Rotate270FlipH => (height, width),
+        _ => (width, height),
+    };
+
+    Ok(ImageInfo {
+        format,
+        width,
+        height,
+    })
+}
+
+/// Remove the GPS position from the EXIF data and drop XMP packets, which may
+/// repeat it. Everything else, e.g. the orientation, is kept. EXIF that can't
+/// be read is dropped as a whole
+pub fn strip_location(format: ImageFormat, data: Vec<u8>) -> Result<Vec<u8>> {
+    match format {
+        ImageFormat::Jpeg => {
+            let mut jpeg = Jpeg::from_bytes(data.into())?;
+            jpeg.segments_mut()
+                .retain(|s| !(s.marker() == APP1 && s.contents().starts_with(XMP_HEADER)));
+            scrub_exif(&mut jpeg);
+            Ok(jpeg.encoder().bytes().to_vec())
+        }
+        ImageFormat::Png => {
+            let mut png = Png::from_bytes(data.into())?;
+            png.chunks_mut().retain(|c| {
+                !(matches!(&c.kind(), b"iTXt" | b"tEXt" | b"zTXt")
+                    && c.contents().starts_with(XMP_KEYWORD))
+            });
+            scrub_exif(&mut png);
+            Ok(png.encoder().bytes().to_vec())
+        }
+        ImageFormat::Webp => strip_webp_location(data),
+        ImageFormat::Gif => Ok(data),
+    }
+}
+
+/// A PNG thumbnail that fits in `size` x `size`, turned as the EXIF orientation
+/// says, `None` if the image is already that small. Animations get their
+/// first frame
+pub fn thumbnail(info: &ImageInfo, data: &[u8], size: u32) -> Result<Option<Vec<u8>>> {
+    if info.width <= size && info.height <= size {
+        return Ok(None);
+    }
+    if info.width as u64 * info.height as u64 > MAX_PIXELS {
+        return Err(ImageError::new("too many pixels"));
+    }
+
+    let mut decoder = reader(info.format, data).into_decoder()?;
+    let orientation = orientation(&mut decoder);
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(Some(encode_png(&image.thumbnail(size, size))?))
+}
+
+fn reader(format: ImageFormat, data: &[u8]) -> ImageReader<Cursor<&[u8]>> {
+    let mut limits = Limits::default();
+    // 4 bytes per pixel, for the largest image that is decoded
+    limits.max_alloc = Some(MAX_PIXELS * 4);
+    let mut reader = ImageReader::with_format(Cursor::new(data), format.into());
+    reader.limits(limits);
+    reader
+}
+
+/// Broken metadata only loses the orientation, the pixels may still be fine
+fn orientation(decoder: &mut impl ImageDecoder) -> Orientation {
+    decoder.orientation().unwrap_or(Orientation::NoTransforms)
+}
+
+fn encode_png(image: &DynamicImage) -> Result<Vec<u8>> {
+    let mut out = Cursor::new(Vec::new());
+    image.write_to(&mut out, ::image::ImageFormat::Png)?;
+    Ok(out.into_inner())
+}
+
+fn scrub_exif(image: &mut impl ImageEXIF) {
+    let Some(exif) = image.exif() else {
+        return;
+    };
+    match without_location(&exif) {
+        Ok(None) => {}
+        Ok(Some(exif)) => image.set_exif(Some(exif.into())),
+        Err(_) => image.set_exif(None),
+    }
+}
+
+/// The EXIF block without its GPS IFD, or the XMP packet and maker note, which
+/// may repeat the location. `None` if there is nothing to remove
+fn without_location(tiff: &[u8]) -> std::result::Result<Option<Vec<u8>>, exif::Error> {
+    let exif = exif::Reader::new().read_raw(tiff.to_vec())?;
+    let removed = |f: &Field| {
+        f.tag.context() == Context::Gps
+            || f.tag == Tag::GPSInfoIFDPointer
+            || f.tag == Tag::MakerNote
+            || (f.tag.context() == Context::Tiff && f.tag.number() == XML_PACKET)
+    };
+    if !exif.fields().any(removed) {
+        return Ok(None);
+    }
+
+    // only a JPEG thumbnail is carried over, the writer can't lay out the others
+    let thumbnail = jpeg_thumbnail(&exif);
+    let mut writer = exif::experimental::Writer::new();
+    for field in exif.fields() {
+        let unknown = matches!(field.value, Value::Unknown(..));
+        if removed(field) || unknown || (field.ifd_num == In::THUMBNAIL && thumbnail.is_none()) {
+            continue;
+        }
+        writer.push_field(field);
+    }
+    if let Some(thumbnail) = thumbnail {
+        writer.set_jpeg(thumbnail, In::THUMBNAIL);
+    }
+    let mut out = Cursor::new(Vec::new());
+    writer.write(&mut out, exif.little_endian())?;
+    Ok(Some(out.into_inner()))
+}
+
+fn jpeg_thumbnail(exif: &exif::Exif) -> Option<&[u8]> {
+    let uint = |tag| exif.get_field(tag, In::THUMBNAIL)?.value.get_uint(0);
+    let offset = uint(Tag::JPEGInterchangeFormat)? as usize;
+    let len = uint(Tag::JPEGInterchangeFormatLength)? as usize;
+    exif.buf().get(offset..offset.checked_add(len)?)
+}
+
+/// img-parts leaves the flags of the extended format as they are, and adds the
+/// JPEG style prefix to EXIF chunks, so the chunks are edited here
+fn strip_webp_location(data: Vec<u8>) -> Result<Vec<u8>> {
+    check_riff(&data)?;
+    let mut webp = WebP::from_bytes(data.into())?;
+    webp.remove_chunks_by_id(CHUNK_XMP);
+    if let Some(exif) = webp
+        .chunk_by_id(CHUNK_EXIF)
+        .and_then(|c| c.content().data())
+        .cloned()
+    {
+        // some writers keep the prefix
+        let prefix = if exif.starts_with(EXIF_HEADER) {
+            EXIF_HEADER
+        } else {
+            &[]
+        };
+        match without_location(&exif[prefix.len()..]) {
+            Ok(None) => {}
+            Ok(Some(tiff)) => {
+                let chunk = RiffChunk::new(
+                    CHUNK_EXIF,
+                    RiffContent::Data(Bytes::from([prefix, &tiff].concat())),
+                );
+                for c in webp
+                    .chunks_mut()
+                    .iter_mut()
+                    .filter(|c| c.id() == CHUNK_EXIF)
+                {
+                    *c = chunk.clone();
+                }
+            }
+            Err(_) => webp.remove_chunks_by_id(CHUNK_EXIF),
+        }
+    }
+
+    let has_exif = webp.has_chunk(CHUNK_EXIF);
+    for chunk in webp.chunks_mut().iter_mut() {
+        let Some(vp8x) = chunk.content().data().filter(|_| chunk.id() == CHUNK_VP8X) else {
+            continue;
+        };
+        let mut vp8x = vp8x.to_vec();
+        if let Some(flags) = vp8x.first_mut() {
+            *flags &= !WEBP_XMP_FLAG;
+            if !has_exif {
+                *flags &= !WEBP_EXIF_FLAG;
+            }
+        }
+        *chunk = RiffChunk::new(CHUNK_VP8X, RiffContent::Data(vp8x.into()));
+    }
+    Ok(webp.encoder().bytes().to_vec())
+}
+
+/// img-parts reads nested RIFF lists recursively, which a crafted file could
+/// make deep enough to overflow the stack. WebP has no lists, so a top level
+/// one is refused
+fn check_riff(data: &[u8]) -> Result<()> {
+    let mut pos = 12;
+    while let Some(header) = data.get(pos..pos + 8) {
+        if matches!(&header[..4], b"RIFF" | b"LIST" | b"seqt") {
+            return Err(ImageError::new("nested riff list"));
+        }
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        pos = pos
+            .saturating_add(8)
+            .saturating_add(len)
+            .saturating_add(len & 1);
+    }
+    Ok(())
+}
+
+/// A PNG of a single color, for tests elsewhere in the crate
+#[cfg(test)]
+pub(crate) fn solid_png(width: u32, height: u32, rgba: [u8; 4]) -> Vec<u8> {
+    let image = ::image::RgbaImage::from_pixel(width, height, ::image::Rgba(rgba));
+    encode_png(&image.into()).expect("png encodes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::image::{Rgba, RgbaImage};
+
+    fn encode(image: &DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        let image = match format {
+            // no alpha in JPEG
+            ImageFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8()),
+            _ => image.clone(),
+        };
+        image.write_to(&mut out, format.into()).unwrap();
+        out.into_inner()
+    }
+
+    /// 300 x 100, red with a white right half
+    fn sample(format: ImageFormat) -> Vec<u8> {
+        let image = RgbaImage::from_fn(300, 100, |x, _| {
+            if x < 150 {
+                Rgba([200, 10, 10, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
+            }
+        });
+        encode(&image.into(), format)
+    }
+
+    /// An EXIF block with an orientation in IFD0 and a GPS latitude
+    fn sample_exif(orientation: u16) -> Vec<u8> {
+        let fields = [
+            Field {
+                tag: Tag::Orientation,
+                ifd_num: In::PRIMARY,
+                value: Value::Short(vec![orientation]),
+            },
+            Field {
+                tag: Tag::GPSLatitudeRef,
+                ifd_num: In::PRIMARY,
+                value: Value::Ascii(vec![b"N".to_vec()]),
+            },
+            Field {
+                tag: Tag::GPSLatitude,
+                ifd_num: In::PRIMARY,
+                value: Value::Rational(vec![(52, 1).into(), (31, 1).into(), (12, 1).into()]),
+            },
+        ];
+        let mut writer = exif::experimental::Writer::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+        let mut out = Cursor::new(Vec::new());
+        writer.write(&mut out, true).unwrap();
+        out.into_inner()
+    }
+
+    fn read_exif(tiff: &[u8]) -> exif::Exif {
+        exif::Reader::new().read_raw(tiff.to_vec()).unwrap()
+    }
+
+    fn assert_no_location(tiff: &[u8]) {
+        let exif = read_exif(tiff);
+        assert!(exif.fields().all(|f| f.tag.context() != Context::Gps));
+        let orientation = exif.get_field(Tag::Orientation, In::PRIMARY).unwrap();
+        assert_eq!(orientation.value.get_uint(0), Some(6));
+    }
+
+    /// Cheap deterministic noise for the fuzz test
+    fn xorshift(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn sniff_should_tell_formats_apart() {
+        assert_eq!(
+            ImageFormat::sniff(b"\x89PNG\r\n\x1a\n...."),
+            Some(ImageFormat::Png)
+        );
+        assert_eq!(
+            ImageFormat::sniff(&[0xFF, 0xD8, 0xFF, 0xE0]),
+            Some(ImageFormat::Jpeg)
+        );
+        assert_eq!(ImageFormat::sniff(b"GIF89a...."), Some(ImageFormat::Gif));
+        assert_eq!(
+            ImageFormat::sniff(b"RIFF\0\0\0\0WEBPVP8 "),
+            Some(ImageFormat::Webp)
+        );
+        assert_eq!(ImageFormat::sniff(b"<html><script>"), None);
+    }
+
+    #[test]
+    fn thumbnail_should_be_made_for_every_format() -> Result<()> {
+        for format in [
+            ImageFormat::Png,
+            ImageFormat::Jpeg,
+            ImageFormat::Gif,
+            ImageFormat::Webp,
+        ] {
+            let data = sample(format);
+            let info = probe(&data)?;
+            assert_eq!((info.format, info.width, info.height), (format, 300, 100));
+
+            let thumb = thumbnail(&info, &data, 64)?.expect("should make a thumbnail");
+            let thumb = ::image::load_from_memory(&thumb)?.to_rgba8();
+            assert_eq!(thumb.dimensions(), (64, 21));
+            let [r, g, b, _] = thumb.get_pixel(0, 10).0;
+            assert!(r > 150 && g < 60 && b < 60, "{:?}: {:?}", format, (r, g, b));
+
+            assert!(thumbnail(&info, &data, 300)?.is_none());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn thumbnail_should_follow_exif_orientation() -> Result<()> {
+        let mut jpeg = Jpeg::from_bytes(sample(ImageFormat::Jpeg).into())?;
+        // turned 90 degrees clockwise to be shown
+        jpeg.set_exif(Some(sample_exif(6).into()));
+        let data = jpeg.encoder().bytes().to_vec();
+
+        let info = probe(&data)?;
+        assert_eq!((info.width, info.height), (100, 300));
+        let thumb = thumbnail(&info, &data, 64)?.expect("should make a thumbnail");
+        let thumb = ::image::load_from_memory(&thumb)?.to_rgba8();
+        assert_eq!(thumb.dimensions(), (21, 64));
+        // the left half, red, is on top now
+        let [r, g, _, _] = thumb.get_pixel(10, 0).0;
+        assert!(r > 150 && g < 60);
+        let [_, g, _, _] = thumb.get_pixel(10, 63).0;
+        assert!(g > 200);
+        Ok(())
+    }
+
+    #[test]
+    fn strip_location_should_remove_gps_and_xmp() -> Result<()> {
+        let exif = sample_exif(6);
+
+        let mut jpeg = Jpeg::from_bytes(sample(ImageFormat::Jpeg).into())?;
+        jpeg.set_exif(Some(exif.clone().into()));
+        let xmp = [XMP_HEADER, b"<x:xmpmeta/>"].concat();
+        jpeg.segments_mut().insert(
+            1,
+            img_parts::jpeg::JpegSegment::new_with_contents(APP1, xmp.into()),
+        );
+        let stripped = strip_location(ImageFormat::Jpeg, jpeg.encoder().bytes().to_vec())?;
+        let jpeg = Jpeg::from_bytes(stripped.clone().into())?;
+        assert_no_location(&jpeg.exif().unwrap());
+        assert!(jpeg
+            .segments()
+            .iter()
+            .all(|s| !s.contents().starts_with(XMP_HEADER)));
+        assert_eq!(probe(&stripped)?.width, 100);
+
+        let mut png = Png::from_bytes(sample(ImageFormat::Png).into())?;
+        png.set_exif(Some(exif.clone().into()));
+        let xmp = [XMP_KEYWORD, b"\0\0\0\0<x:xmpmeta/>"].concat();
+        png.chunks_mut()
+            .insert(1, img_parts::png::PngChunk::new(*b"iTXt", xmp.into()));
+        let stripped = strip_location(ImageFormat::Png, png.encoder().bytes().to_vec())?;
+        let png = Png::from_bytes(stripped.into())?;
+        assert_no_location(&png.exif().unwrap());
+        assert!(png.chunk_by_type(*b"iTXt").is_none());
+
+        // an extended WebP, with the prefix some writers add
+        let mut webp = WebP::from_bytes(sample(ImageFormat::Webp).into())?;
+        webp.set_exif(Some(exif.into()));
+        webp.chunks_mut().push(RiffChunk::new(
+            CHUNK_XMP,
+            RiffContent::Data(Bytes::from_static(b"<x:xmpmeta/>")),
+        ));
+        let stripped = strip_location(ImageFormat::Webp, webp.encoder().bytes().to_vec())?;
+        let webp = WebP::from_bytes(stripped.clone().into())?;
+        let tiff = webp
+            .chunk_by_id(CHUNK_EXIF)
+            .unwrap()
+            .content()
+            .data()
+            .unwrap();
+        assert!(tiff.starts_with(EXIF_HEADER));
+        assert_no_location(&tiff[EXIF_HEADER.len()..]);
+        assert!(!webp.has_chunk(CHUNK_XMP));
+        let flags = webp
+            .chunk_by_id(CHUNK_VP8X)
+            .unwrap()
+            .content()
+            .data()
+            .unwrap()[0];
+        assert_eq!(flags & (WEBP_EXIF_FLAG | WEBP_XMP_FLAG), WEBP_EXIF_FLAG);
+        assert_eq!(
+            u32::from_le_bytes(stripped[4..8].try_into().unwrap()) as usize,
+            stripped.len() - 8
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unreadable_exif_should_be_dropped() -> Result<()> {
+        let mut png = Png::from_bytes(sample(ImageFormat::Png).into())?;
+        png.set_exif(Some(Bytes::from_static(b"II\x2a\x00\xff\xff\xff\xff")));
+        let stripped = strip_location(ImageFormat::Png, png.encoder().bytes().to_vec())?;
+        assert!(Png::from_bytes(stripped.into())?.exif().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn nested_riff_lists_should_be_refused() {
+        let mut data = b"RIFF\0\0\0\0WEBP".to_vec();
+        for _ in 0..100_000 {
+            data.extend(b"LIST\xff\xff\xff\x7fWEBP");
+        }
+        assert!(probe(&data).is_err());
+        assert!(strip_location(ImageFormat::Webp, data).is_err());
+    }
+
+    #[test]
+    fn mangled_images_should_fail_without_panicking() {
+        let mut state = 0x2545_f491_4f6c_dd1d;
+        for format in [
+            ImageFormat::Png,
+            ImageFormat::Jpeg,
+            ImageFormat::Gif,
+            ImageFormat::Webp,
+        ] {
+            let mut data = sample(format);
+            // with EXIF, so that the metadata parsers get mangled input too
+            if format == ImageFormat::Jpeg {
+                let mut jpeg = Jpeg::from_bytes(data.into()).unwrap();
+                jpeg.set_exif(Some(sample_exif(6).into()));
+                data = jpeg.encoder().bytes().to_vec();
+            } else if format == ImageFormat::Png {
+                let mut png = Png::from_bytes(data.into()).unwrap();
+                png.set_exif(Some(sample_exif(6).into()));
+                data = png.encoder().bytes().to_vec();
+            }
+            for round in 0..200 {
+                let mut data = data.clone();
+                match round % 3 {
+                    // flip a few bytes, headers and metadata come first
+                    0 | 1 => {
+                        let span = if round % 3 == 0 { 256 } else { data.len() };
+                        for _ in 0..4 {
+                            let i = xorshift(&mut state) as usize % span.min(data.len());
+                            data[i] = xorshift(&mut state) as u8;
+                        }
+                    }
+                    _ => data.truncate(xorshift(&mut state) as usize % data.len()),
+                }
+                if let Ok(info) = probe(&data) {
+                    let _ = thumbnail(&info, &data, 64);
+                    let _ = strip_location(info.format, data);
+                }
+            }
+        }
+    }
+}
@@ -1,25 +1,32 @@
 mod config;
 mod error;
 mod handlers;
+mod image;
 mod middlewares;
 mod models;
-mod utils;
+mod storage;
 use anyhow::Context;
+use chat_core::{
+    verify_token, CoreError, DecodingKey, EncodingKey, RateLimiter, Session, TokenVerify, User,
+    UserCache,
+};
 pub use error::AppError;
 use handlers::*;
-use middlewares::{set_layer, verify_token};
-pub use models::User;
-use std::{fmt::Debug, ops::Deref, sync::Arc};
-
-use utils::{DecodingKey, EncodingKey};
+use middlewares::set_layer;
+use std::{fmt::Debug, ops::Deref, sync::Arc, time::Duration};
+use storage::FileStorage;
 
 use axum::{
+    extract::DefaultBodyLimit,
     middleware::from_fn_with_state,
-    routing::{get, patch, post},
+    routing::{delete, get, patch, post},
     Router,
 };
 pub use config::AppConfig;
 
+/// a user sends at most one typing event in this time, wherever they type
+const TYPING_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Debug, Clone)]
 pub(crate) struct AppState {
     inner: Arc<AppStateInner>,
@@ -30,12 +37,15 @@ pub(crate) struct AppStateInner {
     pub(crate) ek: EncodingKey,
     pub(crate) dk: DecodingKey,
     pub(crate) pool: sqlx::PgPool,
+    pub(crate) user_cache: UserCache,
+    pub(crate) storage: FileStorage,
+    pub(crate) typing_limiter: RateLimiter,
 }
 
 impl AppState {
     pub async fn try_new(config: AppConfig) -> Result<Self, AppError> {
-        let ek = EncodingKey::load(&config.auth.sk).context("load sk failed")?;
-        let dk = DecodingKey::load(&config.auth.pk).context("load pk failed")?;
+        let (ek, dk) = load_keys(&config)?;
+        let storage = FileStorage::new(&config.upload.storage)?;
         let pool = sqlx::PgPool::connect(&config.server.db_url)
             .await
             .context("load db error")?;
@@ -45,11 +55,22 @@ impl AppState {
                 ek,
                 dk,
                 pool,
+                user_cache: UserCache::default(),
+                storage,
+                typing_limiter: RateLimiter::new(TYPING_INTERVAL),
             }),
         })
     }
 }
 
+/// Tokens are signed with the current key and verified with it or any of the retired keys
+fn load_keys(config: &AppConfig) -> Result<(EncodingKey, DecodingKey), AppError> {
+    let ek = EncodingKey::load(&config.auth.kid, &config.auth.sk).context("load sk failed")?;
+    let mut dk = ek.decoding_key();
+    dk.extend(DecodingKey::load(&config.auth.keys).context("load keys failed")?);
+    Ok((ek, dk))
+}
+
 impl Deref for AppState {
     type Target = Arc<AppStateInner>;
 
@@ -58,6 +79,29 @@ impl Deref for AppState {
     }
 }
 
+impl TokenVerify for AppState {
+    type Error = AppError;
+
+    async fn verify(&self, token: &str) -> Result<User, Self::Error> {
+        let claims = self.dk.verify(token)?;
+        if !Session::is_active(&self.pool, claims.sid).await? {
+            return Err(CoreError::InvalidToken(format!("session {} revoked", claims.sid)).into());
+        }
+        let user = self
+            .user_cache
+            .get(&self.pool, claims.uid)
+            .await?
+            .ok_or_else(|| CoreError::InvalidToken(format!("user {} not found", claims.uid)))?;
+        // the token was issued for another workspace than the user is in now
+        if user.ws_id != claims.wid {
+            return Err(
+                CoreError::InvalidToken(format!("workspace {} mismatch", claims.wid)).into(),
+            );
+        }
+        Ok(user)
+    }
+}
+
 impl Debug for AppStateInner {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("AppState")
@@ -67,35 +111,86 @@ impl Debug for AppStateInner {
 }
 
 pub async fn get_router(config: AppConfig) -> Result<Router, AppError> {
+    // a few files per request, plus the multipart framing
+    let upload_limit = config.upload.max_size * 4;
     let state = AppState::try_new(config).await?;
     let api = Router::new()
         .route("/chat", get(list_chat_handler).post(create_chat_handler))
         .route(
             "/chat/:id",
             patch(update_chat_handler)
+                .get(get_chat_handler)
                 .delete(delete_chat_handler)
                 .post(send_message_handler),
         )
+        .route("/chat/:id/join", post(join_channel_handler))
+        .route("/chat/:id/read", post(mark_chat_read_handler))
+        .route("/chat/:id/typing", post(send_typing_handler))
+        .route(
+            "/chat/:id/members",
+            get(list_chat_members_handler).post(add_chat_members_handler),
+        )
+        .route(
+            "/chat/:id/members/:member_id",
+            patch(update_chat_member_handler).delete(remove_chat_member_handler),
+        )
         .route("/chat/:id/messages", get(list_message_handler))
-        .layer(from_fn_with_state(state.clone(), verify_token))
+        .route(
+            "/chat/:id/messages/:mid",
+            patch(update_message_handler).delete(delete_message_handler),
+        )
+        .route("/chat/:id/messages/:mid/replies", get(list_replies_handler))
+        .route(
+            "/chat/:id/messages/:mid/reactions",
+            post(add_reaction_handler),
+        )
+        .route(
+            "/chat/:id/messages/:mid/reactions/:emoji",
+            delete(remove_reaction_handler),
+        )
+        .route(
+            "/chat/:id/messages/:mid/history",
+            get(list_message_edits_handler),
+        )
+        .route("/channels", get(list_channels_handler))
+        .route("/users", get(list_users_handler))
+        .route("/workspace/invites", post(create_invite_handler))
+        .route("/password", post(change_password_handler))
+        .route(
+            "/upload",
+            post(upload_handler).layer(DefaultBodyLimit::max(upload_limit)),
+        )
+        .route("/files/*path", get(file_handler))
+        .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
         .route("/signin", post(signin_handler))
-        .route("/signup", post(signup_handler));
+        .route("/signup", post(signup_handler))
+        .route("/refresh", post(refresh_handler))
+        .route("/signout", post(signout_handler));
 
     let app = Router::new()
         .route("/", get(index_handler))
+        .route("/.well-known/jwks.json", get(jwks_handler))
         .nest("/api", api)
         .with_state(state);
 
     Ok(set_layer(app))
 }
 
+/// Keeps the test database and the upload dir of a test around
+#[cfg(test)]
+pub(crate) type TestGuard = (sqlx_db_tester::TestPg, tempfile::TempDir);
+
+/// Users signed up by the tests, in this order
+#[cfg(test)]
+const TEST_USERS: [&str; 4] = ["alice", "bob", "charlie", "dave"];
+
 #[cfg(test)]
 impl AppState {
     pub async fn new_for_test(
         config: AppConfig,
     ) -> Result<(sqlx_db_tester::TestPg, Self), AppError> {
-        let ek = EncodingKey::load(&config.auth.sk).context("load sk failed")?;
-        let dk = DecodingKey::load(&config.auth.pk).context("load pk failed")?;
+        let (ek, dk) = load_keys(&config)?;
+        let storage = FileStorage::new(&config.upload.storage)?;
 
         let last_index = config.server.db_url.rfind('/').unwrap();
         let server_url = &config.server.db_url[..last_index];
@@ -113,8 +208,40 @@ impl AppState {
                 ek,
                 dk,
                 pool,
+                user_cache: UserCache::default(),
+                storage,
+                typing_limiter: RateLimiter::new(TYPING_INTERVAL),
             }),
         };
         Ok((tdb, state))
     }
+
+    /// A test state with alice, bob, charlie and dave in the acme workspace,
+    /// uploads go to a temp dir
+    pub async fn new_for_test_with_users(
+        mut config: AppConfig,
+    ) -> Result<(TestGuard, Self, Vec<User>), AppError> {
+        let dir = tempfile::tempdir()?;
+        config.upload.storage = config::StorageConfig::Local {
+            base_dir: dir.path().to_path_buf(),
+        };
+        let (tdb, state) = Self::new_for_test(config).await?;
+        let users = state.create_test_users("acme", TEST_USERS.len()).await?;
+        Ok(((tdb, dir), state, users))
+    }
+
+    /// Sign up the first `count` test users to the workspace `ws`, the first one
+    /// creates it and invites the others
+    pub async fn create_test_users(&self, ws: &str, count: usize) -> Result<Vec<User>, AppError> {
+        let mut users: Vec<User> = Vec::with_capacity(count);
+        for name in &TEST_USERS[..count] {
+            let email = format!("{}@{}.org", name, ws);
+            let mut input = models::CreateUser::new(ws, name, &email, "hunter42");
+            if let Some(first) = users.first() {
+                input.invite = Some(self.create_workspace_invite(first).await?.token);
+            }
+            users.push(self.create_user(&input).await?);
+        }
+        Ok(users)
+    }
 }
@@ -1,8 +1,6 @@
-mod auth;
 mod request_id;
 mod server_time;
 
-pub use auth::verify_token;
 use axum::{middleware::from_fn, Router};
 use server_time::ServerTimeLayer;
 use tower::ServiceBuilder;
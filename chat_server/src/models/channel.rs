@@ -0,0 +1,189 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use chat_core::{Chat, ChatType};
+
+use super::chat::fetch_chat;
+use super::chat_member::notify_chat_member_updated;
+use crate::{AppError, AppState};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 100;
+
+/// A public channel as seen by someone browsing, who may not be a member
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Channel {
+    pub id: i64,
+    pub name: String,
+    pub member_count: i64,
+    pub joined: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Search by name, `last_id` is the last channel of the previous page
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ListChannels {
+    pub q: Option<String>,
+    pub last_id: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+impl AppState {
+    /// List the public channels of the workspace by name
+    pub async fn fetch_public_channels(
+        &self,
+        input: &ListChannels,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<Vec<Channel>, AppError> {
+        let limit = input
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        let pattern = input
+            .q
+            .as_deref()
+            .map(str::trim)
+            .filter(|q| !q.is_empty())
+            .map(|q| format!("%{}%", escape_like(q)));
+
+        let channels = sqlx::query_as(
+            r#"
+                SELECT c.id,c.name,
+                    (SELECT COUNT(*) FROM chat_members m WHERE m.chat_id = c.id) AS member_count,
+                    EXISTS(SELECT 1 FROM chat_members m WHERE m.chat_id = c.id AND m.user_id = $2) AS joined,
+                    c.created_at
+                FROM chats c
+                WHERE c.ws_id = $1 AND c.type = 'public_channel'
+                AND ($3::TEXT IS NULL OR c.name ILIKE $3)
+                AND ($4::BIGINT IS NULL OR (c.name, c.id) > (
+                    SELECT name, id FROM chats WHERE id = $4 AND ws_id = $1
+                ))
+                ORDER BY c.name, c.id
+                LIMIT $5
+            "#,
+        )
+        .bind(ws_id)
+        .bind(user_id)
+        .bind(pattern)
+        .bind(input.last_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(channels)
+    }
+
+    /// Join a public channel of the workspace, joining twice is a no-op.
+    /// Private channels are invite only, they can't be found this way
+    pub async fn join_channel(&self, id: i64, user_id: i64, ws_id: i64) -> Result<Chat, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let (r#type,): (ChatType,) =
+            sqlx::query_as(r#"SELECT type FROM chats WHERE id = $1 AND ws_id = $2"#)
+                .bind(id)
+                .bind(ws_id)
+                .fetch_optional(&mut *tx)
+                .await?
+                .ok_or_else(|| AppError::NotFound(format!("chat id {}", id)))?;
+        if r#type != ChatType::PublicChannel {
+            return Err(AppError::NotFound(format!("chat id {}", id)));
+        }
+
+        let ret = sqlx::query(
+            r#"
+                INSERT INTO chat_members (chat_id,user_id)
+                VALUES ($1,$2)
+                ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+        let chat = fetch_chat(&mut tx, id).await?;
+        if ret.rows_affected() > 0 {
+            notify_chat_member_updated(&mut tx, "ADD", id, &[user_id], None, user_id).await?;
+        }
+        tx.commit().await?;
+
+        Ok(chat)
+    }
+}
+
+fn escape_like(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::CreateChat, AppConfig};
+    use anyhow::Result;
+
+    #[test]
+    fn escape_like_should_escape_wildcards() {
+        assert_eq!(escape_like("dev_ops 100%"), r"dev\_ops 100\%");
+        assert_eq!(escape_like(r"a\b"), r"a\\b");
+    }
+
+    #[tokio::test]
+    async fn public_channels_should_be_discoverable_and_joinable() -> Result<()> {
+        let (_guard, state, users) = AppState::new_for_test_with_users(AppConfig::load()?).await?;
+        let (alice, bob) = (&users[0], &users[1]);
+
+        let input = CreateChat::new("random", ChatType::PublicChannel, &[]);
+        let random = state.create_chat(&input, alice.id, alice.ws_id).await?;
+        let input = CreateChat::new("rust_lang", ChatType::PublicChannel, &[]);
+        state.create_chat(&input, alice.id, alice.ws_id).await?;
+        let input = CreateChat::new("secret", ChatType::PrivateChannel, &[]);
+        let secret = state.create_chat(&input, alice.id, alice.ws_id).await?;
+
+        let channels = state
+            .fetch_public_channels(&ListChannels::default(), bob.id, bob.ws_id)
+            .await?;
+        let names: Vec<_> = channels.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["random", "rust_lang"]);
+        assert!(channels.iter().all(|c| c.member_count == 1 && !c.joined));
+
+        let input = ListChannels {
+            q: Some("T_L".to_string()),
+            ..Default::default()
+        };
+        let channels = state
+            .fetch_public_channels(&input, bob.id, bob.ws_id)
+            .await?;
+        assert_eq!(channels.len(), 1);
+        assert_eq!(channels[0].name, "rust_lang");
+
+        let input = ListChannels {
+            last_id: Some(random.id),
+            limit: Some(1),
+            ..Default::default()
+        };
+        let channels = state
+            .fetch_public_channels(&input, bob.id, bob.ws_id)
+            .await?;
+        assert_eq!(channels[0].name, "rust_lang");
+
+        let chat = state.join_channel(random.id, bob.id, bob.ws_id).await?;
+        assert_eq!(chat.members, vec![alice.id, bob.id]);
+        state.join_channel(random.id, bob.id, bob.ws_id).await?;
+        let channels = state
+            .fetch_public_channels(&ListChannels::default(), bob.id, bob.ws_id)
+            .await?;
+        assert_eq!((channels[0].member_count, channels[0].joined), (2, true));
+
+        let ret = state.join_channel(secret.id, bob.id, bob.ws_id).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        Ok(())
+    }
+}
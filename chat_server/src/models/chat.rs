@@ -0,0 +1,528 @@
+use serde::{Deserialize, Serialize};
+
+use chat_core::{Chat, ChatType};
+
+use super::chat_member::{can_delete, can_rename};
+use crate::{AppError, AppState};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateChat {
+    pub name: String,
+    pub r#type: ChatType,
+    pub members: Vec<i64>,
+}
+
+#[cfg(test)]
+impl CreateChat {
+    pub fn new(name: &str, r#type: ChatType, members: &[i64]) -> Self {
+        Self {
+            name: name.to_string(),
+            r#type,
+            members: members.to_vec(),
+        }
+    }
+}
+
+/// Members change through the /members endpoints only, so a body that still
+/// sends them is rejected instead of silently ignored
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateChat {
+    pub name: Option<String>,
+}
+
+/// Members are aggregated from chat_members, so that a chat keeps the same shape for clients
+const SELECT_CHAT: &str = r#"
+    SELECT c.id,c.ws_id,c.name,c.type,
+        ARRAY(SELECT m.user_id FROM chat_members m WHERE m.chat_id = c.id ORDER BY m.user_id) AS members,
+        c.created_at
+    FROM chats c
+"#;
+
+const CHAT_UPDATED: &str = "chat_updated";
+/// pg_notify fails on payloads of 8000 bytes or more, so users are sent at most this
+/// many at a time
+const NOTIFY_USERS_CHUNK: usize = 256;
+
+impl AppState {
+    /// Create a new chat in the creator's workspace, the creator is its owner
+    pub async fn create_chat(
+        &self,
+        input: &CreateChat,
+        creator_id: i64,
+        ws_id: i64,
+    ) -> Result<Chat, AppError> {
+        let mut members = input.members.clone();
+        members.push(creator_id);
+        let members = normalize_members(members);
+
+        validate_members(input.r#type, &members).map_err(AppError::CreateChatError)?;
+        if !self.members_exist(&members, ws_id).await? {
+            return Err(AppError::CreateChatError(
+                "some members do not exist".to_string(),
+            ));
+        }
+
+        if self.find_chat_by_name(&input.name, ws_id).await?.is_some() {
+            return Err(AppError::ChatAlreadyExists(input.name.clone()));
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let (id,): (i64,) = sqlx::query_as(
+            r#"
+                INSERT INTO chats (ws_id,name,type)
+                VALUES ($1,$2,$3)
+                RETURNING id
+            "#,
+        )
+        .bind(ws_id)
+        .bind(&input.name)
+        .bind(input.r#type)
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+                INSERT INTO chat_members (chat_id,user_id,role)
+                SELECT $1, u, CASE WHEN u = $2 THEN 'owner'::chat_member_role ELSE 'member' END
+                FROM UNNEST($3::BIGINT[]) AS u
+            "#,
+        )
+        .bind(id)
+        .bind(creator_id)
+        .bind(&members)
+        .execute(&mut *tx)
+        .await?;
+
+        let chat = fetch_chat(&mut tx, id).await?;
+        notify_chat_updated(&mut tx, "INSERT", &chat, &[]).await?;
+        tx.commit().await?;
+
+        Ok(chat)
+    }
+
+    /// List all chats the user is a member of
+    pub async fn fetch_chats(&self, user_id: i64, ws_id: i64) -> Result<Vec<Chat>, AppError> {
+        let chats = sqlx::query_as(&format!(
+            r#"
+                {}
+                JOIN chat_members cm ON cm.chat_id = c.id
+                WHERE c.ws_id = $1 AND cm.user_id = $2
+                ORDER BY c.id
+            "#,
+            SELECT_CHAT
+        ))
+        .bind(ws_id)
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(chats)
+    }
+
+    /// Get a chat by id, only if the user is a member of it
+    pub async fn get_chat_by_id(
+        &self,
+        id: i64,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<Option<Chat>, AppError> {
+        if !self.is_chat_member(id, user_id, ws_id).await? {
+            return Ok(None);
+        }
+        let chat = sqlx::query_as(&format!("{} WHERE c.id = $1", SELECT_CHAT))
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(chat)
+    }
+
+    /// Rename a chat the user is a member of, only the owner and admins rename
+    /// groups and channels
+    pub async fn update_chat(
+        &self,
+        id: i64,
+        input: &UpdateChat,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<Chat, AppError> {
+        let (old, caller) = self.get_member_chat(id, user_id, ws_id).await?;
+
+        let name = input.name.clone().unwrap_or_else(|| old.name.clone());
+        if name != old.name && !can_rename(old.r#type, caller.role) {
+            return Err(AppError::PermissionDenied(format!(
+                "can't rename chat id {}",
+                id
+            )));
+        }
+        if let Some(other) = self.find_chat_by_name(&name, ws_id).await? {
+            if other.id != id {
+                return Err(AppError::ChatAlreadyExists(name));
+            }
+        }
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(r#"UPDATE chats SET name = $2 WHERE id = $1"#)
+            .bind(id)
+            .bind(&name)
+            .execute(&mut *tx)
+            .await?;
+
+        let chat = fetch_chat(&mut tx, id).await?;
+        notify_chat_updated(&mut tx, "UPDATE", &chat, &[]).await?;
+        tx.commit().await?;
+
+        Ok(chat)
+    }
+
+    /// Delete a chat together with its messages, only the owner deletes groups
+    /// and channels
+    pub async fn delete_chat(&self, id: i64, user_id: i64, ws_id: i64) -> Result<(), AppError> {
+        let (chat, caller) = self.get_member_chat(id, user_id, ws_id).await?;
+        if !can_delete(chat.r#type, caller.role) {
+            return Err(AppError::PermissionDenied(format!(
+                "can't delete chat id {}",
+                id
+            )));
+        }
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(r#"DELETE FROM messages WHERE chat_id = $1"#)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        // members are deleted by the cascade
+        sqlx::query(r#"DELETE FROM chats WHERE id = $1"#)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        notify_chat_updated(&mut tx, "DELETE", &chat, &chat.members).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Check whether the user is a member of the chat
+    pub async fn is_chat_member(
+        &self,
+        id: i64,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<bool, AppError> {
+        let (is_member,): (bool,) = sqlx::query_as(
+            r#"
+                SELECT EXISTS(
+                    SELECT 1 FROM chat_members m
+                    JOIN chats c ON c.id = m.chat_id
+                    WHERE m.chat_id = $1 AND c.ws_id = $2 AND m.user_id = $3
+                )
+            "#,
+        )
+        .bind(id)
+        .bind(ws_id)
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(is_member)
+    }
+
+    async fn find_chat_by_name(&self, name: &str, ws_id: i64) -> Result<Option<Chat>, AppError> {
+        let chat = sqlx::query_as(&format!(
+            "{} WHERE c.ws_id = $1 AND c.name = $2",
+            SELECT_CHAT
+        ))
+        .bind(ws_id)
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(chat)
+    }
+
+    /// Members must exist and belong to the workspace of the chat
+    pub(super) async fn members_exist(
+        &self,
+        members: &[i64],
+        ws_id: i64,
+    ) -> Result<bool, AppError> {
+        let (count,): (i64,) =
+            sqlx::query_as(r#"SELECT COUNT(*) FROM users WHERE ws_id = $1 AND id = ANY($2)"#)
+                .bind(ws_id)
+                .bind(members)
+                .fetch_one(&self.pool)
+                .await?;
+
+        Ok(count as usize == members.len())
+    }
+}
+
+pub(super) async fn fetch_chat(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    id: i64,
+) -> Result<Chat, AppError> {
+    let chat = sqlx::query_as(&format!("{} WHERE c.id = $1", SELECT_CHAT))
+        .bind(id)
+        .fetch_one(&mut **tx)
+        .await?;
+
+    Ok(chat)
+}
+
+/// Tell notify_server about the change, delivered only once the transaction commits.
+/// Only the id goes out, notify_server loads the chat and its members. `users` are
+/// the ones it can't find that way, removed members or all of a deleted chat
+pub(super) async fn notify_chat_updated(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    op: &str,
+    chat: &Chat,
+    users: &[i64],
+) -> Result<(), AppError> {
+    let mut payload = serde_json::json!({ "op": op, "id": chat.id });
+    if op == "DELETE" {
+        // gone by the time notify_server would load it, the name is at most 128 chars
+        let chat = Chat {
+            members: Vec::new(),
+            ..chat.clone()
+        };
+        payload["chat"] = serde_json::json!(chat);
+    }
+    pg_notify_users(tx, CHAT_UPDATED, payload, "users", users).await
+}
+
+/// Send the payload with `users` under `key` in chunks, numbered by `chunk`, so that
+/// no payload gets near the limit of pg_notify. At least one is sent
+pub(super) async fn pg_notify_users(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    channel: &str,
+    mut payload: serde_json::Value,
+    key: &str,
+    users: &[i64],
+) -> Result<(), AppError> {
+    let mut chunks: Vec<&[i64]> = users.chunks(NOTIFY_USERS_CHUNK).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        payload[key] = serde_json::json!(chunk);
+        payload["chunk"] = serde_json::json!(i);
+        pg_notify(tx, channel, &payload).await?;
+    }
+
+    Ok(())
+}
+
+pub(super) async fn pg_notify(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    channel: &str,
+    payload: &serde_json::Value,
+) -> Result<(), AppError> {
+    sqlx::query(r#"SELECT pg_notify($1, $2)"#)
+        .bind(channel)
+        .bind(payload.to_string())
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+pub(super) fn normalize_members(mut members: Vec<i64>) -> Vec<i64> {
+    members.sort_unstable();
+    members.dedup();
+    members
+}
+
+/// Check the member count rules of each chat type
+pub(super) fn validate_members(chat_type: ChatType, members: &[i64]) -> Result<(), String> {
+    let len = members.len();
+    match chat_type {
+        ChatType::Single if len != 2 => Err(format!(
+            "single chat must have exactly 2 members, got {}",
+            len
+        )),
+        ChatType::Group if len < 3 => Err(format!(
+            "group chat must have at least 3 members, got {}",
+            len
+        )),
+        ChatType::PrivateChannel | ChatType::PublicChannel if len < 1 => {
+            Err("channel must have at least 1 member".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::AddChatMembers, AppConfig};
+    use anyhow::Result;
+    use chat_core::{ChatMember, ChatMemberRole};
+
+    #[test]
+    fn update_chat_should_reject_members() {
+        let input: UpdateChat = serde_json::from_str(r#"{"name":"random"}"#).unwrap();
+        assert_eq!(input.name.as_deref(), Some("random"));
+        let ret = serde_json::from_str::<UpdateChat>(r#"{"name":"random","members":[1,2,3]}"#);
+        assert!(ret.is_err());
+    }
+
+    #[test]
+    fn validate_members_should_follow_chat_type_rules() {
+        assert!(validate_members(ChatType::Single, &[1, 2]).is_ok());
+        assert!(validate_members(ChatType::Single, &[1, 2, 3]).is_err());
+        assert!(validate_members(ChatType::Group, &[1, 2]).is_err());
+        assert!(validate_members(ChatType::Group, &[1, 2, 3]).is_ok());
+        assert!(validate_members(ChatType::PublicChannel, &[1]).is_ok());
+        assert!(validate_members(ChatType::PrivateChannel, &[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn chat_crud_should_work() -> Result<()> {
+        let (_guard, state, users) = AppState::new_for_test_with_users(AppConfig::load()?).await?;
+        let ws_id = users[0].ws_id;
+        let ids: Vec<i64> = users.iter().map(|u| u.id).collect();
+
+        let input = CreateChat::new("general", ChatType::Group, &[ids[1], ids[2]]);
+        let chat = state.create_chat(&input, ids[0], ws_id).await?;
+        assert_eq!(chat.name, "general");
+        assert_eq!(chat.ws_id, ws_id);
+        assert_eq!(chat.members, vec![ids[0], ids[1], ids[2]]);
+
+        let chats = state.fetch_chats(ids[1], ws_id).await?;
+        assert_eq!(chats, vec![chat.clone()]);
+        assert!(state.fetch_chats(ids[3], ws_id).await?.is_empty());
+        assert!(state
+            .get_chat_by_id(chat.id, ids[3], ws_id)
+            .await?
+            .is_none());
+
+        let input = UpdateChat {
+            name: Some("random".to_string()),
+        };
+        let chat = state.update_chat(chat.id, &input, ids[0], ws_id).await?;
+        assert_eq!(chat.name, "random");
+        assert_eq!(chat.members.len(), 3);
+
+        let input = AddChatMembers {
+            members: vec![ids[3]],
+        };
+        let chat = state
+            .add_chat_members(chat.id, &input, ids[0], ws_id)
+            .await?;
+        assert_eq!(chat.members.len(), 4);
+
+        // plain members of a group can neither rename nor delete it
+        let input = UpdateChat {
+            name: Some("off-topic".to_string()),
+        };
+        let ret = state.update_chat(chat.id, &input, ids[3], ws_id).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ret = state.delete_chat(chat.id, ids[3], ws_id).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        state.delete_chat(chat.id, ids[0], ws_id).await?;
+        assert!(state
+            .get_chat_by_id(chat.id, ids[0], ws_id)
+            .await?
+            .is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_chat_should_reject_invalid_input() -> Result<()> {
+        let (_guard, state, users) = AppState::new_for_test_with_users(AppConfig::load()?).await?;
+        let ws_id = users[0].ws_id;
+        let ids: Vec<i64> = users.iter().map(|u| u.id).collect();
+
+        let input = CreateChat::new("pair", ChatType::Single, &[ids[1], ids[2]]);
+        let ret = state.create_chat(&input, ids[0], ws_id).await;
+        assert!(matches!(ret, Err(AppError::CreateChatError(_))));
+
+        let input = CreateChat::new("ghost", ChatType::Single, &[9999]);
+        let ret = state.create_chat(&input, ids[0], ws_id).await;
+        assert!(matches!(ret, Err(AppError::CreateChatError(_))));
+
+        let input = CreateChat::new("pair", ChatType::Single, &[ids[1]]);
+        state.create_chat(&input, ids[0], ws_id).await?;
+        let ret = state.create_chat(&input, ids[2], ws_id).await;
+        assert!(matches!(ret, Err(AppError::ChatAlreadyExists(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chats_should_be_scoped_to_workspace() -> Result<()> {
+        let (_guard, state, users) = AppState::new_for_test_with_users(AppConfig::load()?).await?;
+        let others = state.create_test_users("initech", 2).await?;
+        let (acme, initech) = (users[0].ws_id, others[0].ws_id);
+        let acme_ids: Vec<i64> = users.iter().map(|u| u.id).collect();
+        let initech_ids: Vec<i64> = others.iter().map(|u| u.id).collect();
+        assert_ne!(acme, initech);
+
+        // the same name can be used in another workspace
+        let input = CreateChat::new("general", ChatType::PublicChannel, &[acme_ids[1]]);
+        let chat = state.create_chat(&input, acme_ids[0], acme).await?;
+        let input = CreateChat::new("general", ChatType::PublicChannel, &[initech_ids[1]]);
+        state.create_chat(&input, initech_ids[0], initech).await?;
+
+        // members can't come from another workspace
+        let input = CreateChat::new("pair", ChatType::Single, &[initech_ids[0]]);
+        let ret = state.create_chat(&input, acme_ids[0], acme).await;
+        assert!(matches!(ret, Err(AppError::CreateChatError(_))));
+
+        // a chat is not visible through another workspace
+        assert!(state
+            .get_chat_by_id(chat.id, acme_ids[0], initech)
+            .await?
+            .is_none());
+        assert!(!state.is_chat_member(chat.id, acme_ids[0], initech).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chat_members_should_keep_roles() -> Result<()> {
+        let (_guard, state, users) = AppState::new_for_test_with_users(AppConfig::load()?).await?;
+        let ws = users[0].ws_id;
+        let ids: Vec<i64> = users.iter().map(|u| u.id).collect();
+
+        let input = CreateChat::new("general", ChatType::PublicChannel, &[ids[1], ids[2]]);
+        let chat = state.create_chat(&input, ids[0], ws).await?;
+
+        state
+            .remove_chat_member(chat.id, ids[1], ids[0], ws)
+            .await?;
+        let input = AddChatMembers {
+            members: vec![ids[3]],
+        };
+        let chat = state.add_chat_members(chat.id, &input, ids[0], ws).await?;
+        assert_eq!(chat.members, vec![ids[0], ids[2], ids[3]]);
+
+        let members: Vec<ChatMember> = sqlx::query_as(
+            "SELECT chat_id,user_id,role,joined_at,last_read_message_id FROM chat_members WHERE chat_id = $1 ORDER BY user_id",
+        )
+        .bind(chat.id)
+        .fetch_all(&state.pool)
+        .await?;
+        let roles: Vec<_> = members.iter().map(|m| (m.user_id, m.role)).collect();
+        assert_eq!(
+            roles,
+            vec![
+                (ids[0], ChatMemberRole::Owner),
+                (ids[2], ChatMemberRole::Member),
+                (ids[3], ChatMemberRole::Member),
+            ]
+        );
+
+        state.delete_chat(chat.id, ids[0], ws).await?;
+        let (count,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM chat_members WHERE chat_id = $1")
+                .bind(chat.id)
+                .fetch_one(&state.pool)
+                .await?;
+        assert_eq!(count, 0);
+
+        Ok(())
+    }
+}
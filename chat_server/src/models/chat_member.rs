@@ -0,0 +1,525 @@
+use serde::{Deserialize, Serialize};
+
+use chat_core::{Chat, ChatMember, ChatMemberRole, ChatType};
+
+use super::chat::{
+    fetch_chat, normalize_members, notify_chat_updated, pg_notify_users, validate_members,
+};
+use crate::{AppError, AppState};
+
+const CHAT_MEMBER_UPDATED: &str = "chat_member_updated";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AddChatMembers {
+    pub members: Vec<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateChatMember {
+    pub role: ChatMemberRole,
+}
+
+impl AppState {
+    /// List the members of a chat the user is a member of, ordered by join time
+    pub async fn fetch_chat_members(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<Vec<ChatMember>, AppError> {
+        self.get_member_chat(chat_id, user_id, ws_id).await?;
+        let members = sqlx::query_as(
+            r#"
+                SELECT chat_id,user_id,role,joined_at,last_read_message_id
+                FROM chat_members
+                WHERE chat_id = $1
+                ORDER BY joined_at, user_id
+            "#,
+        )
+        .bind(chat_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(members)
+    }
+
+    /// Invite users of the workspace to a chat, users already in it are skipped
+    pub async fn add_chat_members(
+        &self,
+        chat_id: i64,
+        input: &AddChatMembers,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<Chat, AppError> {
+        let (chat, caller) = self.get_member_chat(chat_id, user_id, ws_id).await?;
+        if !can_invite(chat.r#type, caller.role) {
+            return Err(AppError::PermissionDenied(format!(
+                "can't invite members to chat id {}",
+                chat_id
+            )));
+        }
+
+        let added: Vec<i64> = normalize_members(input.members.clone())
+            .into_iter()
+            .filter(|id| !chat.members.contains(id))
+            .collect();
+        if added.is_empty() {
+            return Ok(chat);
+        }
+        if !self.members_exist(&added, ws_id).await? {
+            return Err(AppError::UpdateChatError(
+                "some members do not exist".to_string(),
+            ));
+        }
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+                INSERT INTO chat_members (chat_id,user_id)
+                SELECT $1, u FROM UNNEST($2::BIGINT[]) AS u
+                ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(chat_id)
+        .bind(&added)
+        .execute(&mut *tx)
+        .await?;
+        let chat = fetch_chat(&mut tx, chat_id).await?;
+        notify_chat_member_updated(&mut tx, "ADD", chat_id, &added, None, user_id).await?;
+        tx.commit().await?;
+
+        Ok(chat)
+    }
+
+    /// Remove a member from a chat, or leave it when `member_id` is the user.
+    /// An owner who leaves hands the chat over to the longest standing admin, or
+    /// member if there is none. The last member to leave deletes the chat, a
+    /// group can't drop below 3 members either way
+    pub async fn remove_chat_member(
+        &self,
+        chat_id: i64,
+        member_id: i64,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<(), AppError> {
+        let (chat, caller) = self.get_member_chat(chat_id, user_id, ws_id).await?;
+        if chat.r#type == ChatType::Single {
+            return Err(AppError::PermissionDenied(
+                "members of a single chat can't change".to_string(),
+            ));
+        }
+
+        if member_id == user_id {
+            return self.leave_chat(chat, caller).await;
+        }
+
+        let Some(target) = self.get_chat_member(chat_id, member_id).await? else {
+            return Err(AppError::NotFound(format!(
+                "member id {} of chat id {}",
+                member_id, chat_id
+            )));
+        };
+        if !can_remove(caller.role, target.role) {
+            return Err(AppError::PermissionDenied(format!(
+                "can't remove member id {} from chat id {}",
+                member_id, chat_id
+            )));
+        }
+        let members: Vec<i64> = chat
+            .members
+            .iter()
+            .copied()
+            .filter(|id| *id != member_id)
+            .collect();
+        validate_members(chat.r#type, &members).map_err(AppError::UpdateChatError)?;
+
+        let mut tx = self.pool.begin().await?;
+        delete_chat_member(&mut tx, chat_id, member_id).await?;
+        notify_chat_member_updated(&mut tx, "REMOVE", chat_id, &[member_id], None, user_id).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Promote a member to admin or demote an admin, only the owner can do this
+    pub async fn update_chat_member(
+        &self,
+        chat_id: i64,
+        member_id: i64,
+        input: &UpdateChatMember,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<ChatMember, AppError> {
+        let (chat, caller) = self.get_member_chat(chat_id, user_id, ws_id).await?;
+        if chat.r#type == ChatType::Single || caller.role != ChatMemberRole::Owner {
+            return Err(AppError::PermissionDenied(format!(
+                "can't change roles in chat id {}",
+                chat_id
+            )));
+        }
+        if input.role == ChatMemberRole::Owner {
+            return Err(AppError::UpdateChatError(
+                "a chat has exactly one owner".to_string(),
+            ));
+        }
+        if member_id == user_id {
+            return Err(AppError::UpdateChatError(
+                "the owner's role can't change".to_string(),
+            ));
+        }
+        if self.get_chat_member(chat_id, member_id).await?.is_none() {
+            return Err(AppError::NotFound(format!(
+                "member id {} of chat id {}",
+                member_id, chat_id
+            )));
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let member = set_chat_member_role(&mut tx, chat_id, member_id, input.role).await?;
+        notify_chat_member_updated(
+            &mut tx,
+            "ROLE",
+            chat_id,
+            &[member_id],
+            Some(input.role),
+            user_id,
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(member)
+    }
+
+    async fn leave_chat(&self, chat: Chat, member: ChatMember) -> Result<(), AppError> {
+        let members: Vec<i64> = chat
+            .members
+            .iter()
+            .copied()
+            .filter(|id| *id != member.user_id)
+            .collect();
+        // a group keeps its 3 members like any other change, only the last one
+        // out of a channel takes it down
+        if !members.is_empty() {
+            validate_members(chat.r#type, &members).map_err(AppError::UpdateChatError)?;
+        }
+
+        let mut tx = self.pool.begin().await?;
+        delete_chat_member(&mut tx, chat.id, member.user_id).await?;
+
+        if members.is_empty() {
+            sqlx::query(r#"DELETE FROM messages WHERE chat_id = $1"#)
+                .bind(chat.id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query(r#"DELETE FROM chats WHERE id = $1"#)
+                .bind(chat.id)
+                .execute(&mut *tx)
+                .await?;
+            notify_chat_updated(&mut tx, "DELETE", &chat, &chat.members).await?;
+            tx.commit().await?;
+            return Ok(());
+        }
+
+        notify_chat_member_updated(
+            &mut tx,
+            "REMOVE",
+            chat.id,
+            &[member.user_id],
+            None,
+            member.user_id,
+        )
+        .await?;
+        if member.role == ChatMemberRole::Owner {
+            // owner sorts before admin, and admin before member
+            let (heir,): (i64,) = sqlx::query_as(
+                r#"
+                    SELECT user_id FROM chat_members
+                    WHERE chat_id = $1
+                    ORDER BY role, joined_at, user_id
+                    LIMIT 1
+                "#,
+            )
+            .bind(chat.id)
+            .fetch_one(&mut *tx)
+            .await?;
+            set_chat_member_role(&mut tx, chat.id, heir, ChatMemberRole::Owner).await?;
+            notify_chat_member_updated(
+                &mut tx,
+                "ROLE",
+                chat.id,
+                &[heir],
+                Some(ChatMemberRole::Owner),
+                member.user_id,
+            )
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Get a chat together with the user's membership, not found for non members
+    pub(super) async fn get_member_chat(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<(Chat, ChatMember), AppError> {
+        let chat = self.get_chat_by_id(chat_id, user_id, ws_id).await?;
+        let member = self.get_chat_member(chat_id, user_id).await?;
+        match (chat, member) {
+            (Some(chat), Some(member)) => Ok((chat, member)),
+            _ => Err(AppError::NotFound(format!("chat id {}", chat_id))),
+        }
+    }
+
+    async fn get_chat_member(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+    ) -> Result<Option<ChatMember>, AppError> {
+        let member = sqlx::query_as(
+            r#"
+                SELECT chat_id,user_id,role,joined_at,last_read_message_id
+                FROM chat_members
+                WHERE chat_id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(chat_id)
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(member)
+    }
+}
+
+async fn delete_chat_member(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    chat_id: i64,
+    user_id: i64,
+) -> Result<(), AppError> {
+    sqlx::query(r#"DELETE FROM chat_members WHERE chat_id = $1 AND user_id = $2"#)
+        .bind(chat_id)
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+async fn set_chat_member_role(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    chat_id: i64,
+    user_id: i64,
+    role: ChatMemberRole,
+) -> Result<ChatMember, AppError> {
+    let member = sqlx::query_as(
+        r#"
+            UPDATE chat_members SET role = $3
+            WHERE chat_id = $1 AND user_id = $2
+            RETURNING chat_id,user_id,role,joined_at,last_read_message_id
+        "#,
+    )
+    .bind(chat_id)
+    .bind(user_id)
+    .bind(role)
+    .fetch_one(&mut **tx)
+    .await?;
+
+    Ok(member)
+}
+
+/// Tell notify_server which members were added, removed or given a role. It loads
+/// the chat after the change and notifies its members and the affected users
+pub(super) async fn notify_chat_member_updated(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    op: &str,
+    chat_id: i64,
+    user_ids: &[i64],
+    role: Option<ChatMemberRole>,
+    by: i64,
+) -> Result<(), AppError> {
+    let payload = serde_json::json!({
+        "op": op,
+        "chat_id": chat_id,
+        "role": role,
+        "by": by,
+    });
+    pg_notify_users(tx, CHAT_MEMBER_UPDATED, payload, "user_ids", user_ids).await
+}
+
+/// Anyone in a group or public channel can invite, private channels are curated
+fn can_invite(chat_type: ChatType, role: ChatMemberRole) -> bool {
+    match chat_type {
+        ChatType::Single => false,
+        ChatType::Group | ChatType::PublicChannel => true,
+        ChatType::PrivateChannel => role != ChatMemberRole::Member,
+    }
+}
+
+/// Both members of a single chat can rename it, the owner and admins otherwise
+pub(super) fn can_rename(chat_type: ChatType, role: ChatMemberRole) -> bool {
+    chat_type == ChatType::Single || role != ChatMemberRole::Member
+}
+
+/// Both members of a single chat can delete it, only the owner otherwise
+pub(super) fn can_delete(chat_type: ChatType, role: ChatMemberRole) -> bool {
+    chat_type == ChatType::Single || role == ChatMemberRole::Owner
+}
+
+/// The owner can remove anyone else, admins can remove plain members
+fn can_remove(role: ChatMemberRole, target: ChatMemberRole) -> bool {
+    matches!(
+        (role, target),
+        (
+            ChatMemberRole::Owner,
+            ChatMemberRole::Admin | ChatMemberRole::Member
+        ) | (ChatMemberRole::Admin, ChatMemberRole::Member)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::CreateChat, AppConfig};
+    use anyhow::Result;
+
+    #[test]
+    fn permissions_should_follow_chat_type_and_role() {
+        use ChatMemberRole::*;
+
+        assert!(!can_invite(ChatType::Single, Owner));
+        assert!(can_invite(ChatType::Group, Member));
+        assert!(can_invite(ChatType::PublicChannel, Member));
+        assert!(!can_invite(ChatType::PrivateChannel, Member));
+        assert!(can_invite(ChatType::PrivateChannel, Admin));
+
+        assert!(can_remove(Owner, Admin));
+        assert!(can_remove(Admin, Member));
+        assert!(!can_remove(Admin, Admin));
+        assert!(!can_remove(Admin, Owner));
+        assert!(!can_remove(Member, Member));
+
+        assert!(can_rename(ChatType::Single, Member));
+        assert!(can_rename(ChatType::Group, Admin));
+        assert!(!can_rename(ChatType::PublicChannel, Member));
+        assert!(can_delete(ChatType::Single, Member));
+        assert!(can_delete(ChatType::Group, Owner));
+        assert!(!can_delete(ChatType::Group, Admin));
+    }
+
+    #[tokio::test]
+    async fn chat_members_should_be_managed_by_role() -> Result<()> {
+        let (_guard, state, users) = AppState::new_for_test_with_users(AppConfig::load()?).await?;
+        let ws = users[0].ws_id;
+        let ids: Vec<i64> = users.iter().map(|u| u.id).collect();
+        let input = CreateChat::new("ops", ChatType::PrivateChannel, &[ids[1]]);
+        let chat = state.create_chat(&input, ids[0], ws).await?;
+
+        // plain members can't invite to a private channel
+        let input = AddChatMembers {
+            members: vec![ids[2]],
+        };
+        let ret = state.add_chat_members(chat.id, &input, ids[1], ws).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let input = UpdateChatMember {
+            role: ChatMemberRole::Admin,
+        };
+        let ret = state
+            .update_chat_member(chat.id, ids[0], &input, ids[1], ws)
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let member = state
+            .update_chat_member(chat.id, ids[1], &input, ids[0], ws)
+            .await?;
+        assert_eq!(member.role, ChatMemberRole::Admin);
+
+        let input = AddChatMembers {
+            members: vec![ids[2], ids[3]],
+        };
+        let chat = state.add_chat_members(chat.id, &input, ids[1], ws).await?;
+        assert_eq!(chat.members, vec![ids[0], ids[1], ids[2], ids[3]]);
+
+        // admins can remove members but not the owner
+        state
+            .remove_chat_member(chat.id, ids[3], ids[1], ws)
+            .await?;
+        let ret = state.remove_chat_member(chat.id, ids[0], ids[1], ws).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let members = state.fetch_chat_members(chat.id, ids[2], ws).await?;
+        assert_eq!(
+            members.iter().map(|m| m.user_id).collect::<Vec<_>>(),
+            vec![ids[0], ids[1], ids[2]]
+        );
+        let ret = state.fetch_chat_members(chat.id, ids[3], ws).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn leaving_owner_should_hand_over_chat() -> Result<()> {
+        let (_guard, state, users) = AppState::new_for_test_with_users(AppConfig::load()?).await?;
+        let ws = users[0].ws_id;
+        let ids: Vec<i64> = users.iter().map(|u| u.id).collect();
+        let input = CreateChat::new("general", ChatType::PublicChannel, &[ids[1], ids[2]]);
+        let chat = state.create_chat(&input, ids[0], ws).await?;
+        let input = UpdateChatMember {
+            role: ChatMemberRole::Admin,
+        };
+        state
+            .update_chat_member(chat.id, ids[2], &input, ids[0], ws)
+            .await?;
+
+        state
+            .remove_chat_member(chat.id, ids[0], ids[0], ws)
+            .await?;
+        let members = state.fetch_chat_members(chat.id, ids[1], ws).await?;
+        let roles: Vec<_> = members.iter().map(|m| (m.user_id, m.role)).collect();
+        assert_eq!(
+            roles,
+            vec![
+                (ids[1], ChatMemberRole::Member),
+                (ids[2], ChatMemberRole::Owner),
+            ]
+        );
+
+        // the last one out deletes the chat
+        state
+            .remove_chat_member(chat.id, ids[1], ids[1], ws)
+            .await?;
+        state
+            .remove_chat_member(chat.id, ids[2], ids[2], ws)
+            .await?;
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM chats WHERE id = $1")
+            .bind(chat.id)
+            .fetch_one(&state.pool)
+            .await?;
+        assert_eq!(count, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn group_should_keep_three_members_when_one_leaves() -> Result<()> {
+        let (_guard, state, users) = AppState::new_for_test_with_users(AppConfig::load()?).await?;
+        let ws = users[0].ws_id;
+        let ids: Vec<i64> = users.iter().map(|u| u.id).collect();
+        let input = CreateChat::new("team", ChatType::Group, &[ids[1], ids[2], ids[3]]);
+        let chat = state.create_chat(&input, ids[0], ws).await?;
+
+        state
+            .remove_chat_member(chat.id, ids[3], ids[3], ws)
+            .await?;
+        let ret = state.remove_chat_member(chat.id, ids[2], ids[2], ws).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+
+        let members = state.fetch_chat_members(chat.id, ids[2], ws).await?;
+        assert_eq!(members.len(), 3);
+
+        Ok(())
+    }
+}
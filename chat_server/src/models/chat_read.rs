@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+
+use chat_core::{Chat, ChatRead, Message, UNREAD_COUNT};
+use serde::{Deserialize, Serialize};
+
+use super::message::MESSAGE_COLUMNS;
+use crate::{AppError, AppState};
+
+/// A chat as listed for a user, with what they haven't read yet
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ChatSummary {
+    #[serde(flatten)]
+    pub chat: Chat,
+    pub last_read_message_id: Option<i64>,
+    pub unread_count: i64,
+    pub last_message: Option<Message>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MarkChatRead {
+    pub message_id: i64,
+}
+
+impl AppState {
+    /// List the chats of the user with their unread counts and latest message
+    pub async fn fetch_chat_summaries(
+        &self,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<Vec<ChatSummary>, AppError> {
+        let chats = self.fetch_chats(user_id, ws_id).await?;
+        let ids: Vec<i64> = chats.iter().map(|c| c.id).collect();
+
+        let rows: Vec<(i64, Option<i64>, i64, Option<i64>)> = sqlx::query_as(&format!(
+            r#"
+                SELECT cm.chat_id,cm.last_read_message_id,{},
+                    (
+                        SELECT m.id FROM messages m
+                        WHERE m.chat_id = cm.chat_id AND m.parent_id IS NULL
+                        AND m.deleted_at IS NULL
+                        ORDER BY m.created_at DESC, m.id DESC
+                        LIMIT 1
+                    )
+                FROM chat_members cm
+                WHERE cm.user_id = $1 AND cm.chat_id = ANY($2)
+            "#,
+            UNREAD_COUNT
+        ))
+        .bind(user_id)
+        .bind(&ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let last_ids: Vec<i64> = rows.iter().filter_map(|r| r.3).collect();
+        let mut last_messages: Vec<Message> = sqlx::query_as(&format!(
+            "SELECT {} FROM messages WHERE id = ANY($1)",
+            MESSAGE_COLUMNS
+        ))
+        .bind(&last_ids)
+        .fetch_all(&self.pool)
+        .await?;
+        Message::load_attachments(&self.pool, &mut last_messages).await?;
+        let mut last_messages: HashMap<i64, Message> =
+            last_messages.into_iter().map(|m| (m.chat_id, m)).collect();
+        let mut rows: HashMap<i64, (Option<i64>, i64)> = rows
+            .into_iter()
+            .map(|(chat_id, last_read, unread, _)| (chat_id, (last_read, unread)))
+            .collect();
+
+        let summaries = chats
+            .into_iter()
+            .map(|chat| {
+                let (last_read_message_id, unread_count) =
+                    rows.remove(&chat.id).unwrap_or_default();
+                ChatSummary {
+                    last_message: last_messages.remove(&chat.id),
+                    chat,
+                    last_read_message_id,
+                    unread_count,
+                }
+            })
+            .collect();
+        Ok(summaries)
+    }
+
+    /// Move the read cursor of the user forward to a message of the chat. The
+    /// other devices of the user are told, so that they clear their badges too
+    pub async fn mark_chat_read(
+        &self,
+        input: &MarkChatRead,
+        chat_id: i64,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<ChatRead, AppError> {
+        if !self.is_chat_member(chat_id, user_id, ws_id).await? {
+            return Err(AppError::PermissionDenied(format!(
+                "user {} is not a member of chat {}",
+                user_id, chat_id
+            )));
+        }
+
+        let mut tx = self.pool.begin().await?;
+        // reading an older message on another device is a no-op
+        let (read, moved) = ChatRead::mark(&mut tx, chat_id, user_id, input.message_id).await?;
+        if !moved && read.last_read_message_id < Some(input.message_id) {
+            return Err(AppError::NotFound(format!(
+                "message id {}",
+                input.message_id
+            )));
+        }
+        tx.commit().await?;
+
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{CreateChat, CreateMessage},
+        AppConfig,
+    };
+    use anyhow::Result;
+    use chat_core::ChatType;
+
+    #[tokio::test]
+    async fn read_cursor_should_drive_unread_counts() -> Result<()> {
+        let (_guard, state, users) = AppState::new_for_test_with_users(AppConfig::load()?).await?;
+        let ws_id = users[0].ws_id;
+        let (alice, bob) = (users[0].id, users[1].id);
+        let input = CreateChat::new("pair", ChatType::Single, &[bob]);
+        let chat = state.create_chat(&input, alice, ws_id).await?;
+
+        let mut messages = Vec::new();
+        for i in 0..3 {
+            let input = CreateMessage::new(&format!("ping {}", i), &[]);
+            messages.push(state.create_message(&input, chat.id, alice, ws_id).await?);
+        }
+        // replies and own messages don't count
+        let input = CreateMessage::reply(messages[0].id, "in a thread");
+        state.create_message(&input, chat.id, alice, ws_id).await?;
+        let input = CreateMessage::new("pong", &[]);
+        let pong = state.create_message(&input, chat.id, bob, ws_id).await?;
+
+        let summaries = state.fetch_chat_summaries(bob, ws_id).await?;
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].chat, chat);
+        assert_eq!(summaries[0].unread_count, 3);
+        assert_eq!(summaries[0].last_read_message_id, None);
+        assert_eq!(
+            summaries[0].last_message.as_ref().map(|m| m.id),
+            Some(pong.id)
+        );
+        let summaries = state.fetch_chat_summaries(alice, ws_id).await?;
+        assert_eq!(summaries[0].unread_count, 1);
+
+        let input = MarkChatRead {
+            message_id: messages[1].id,
+        };
+        let read = state.mark_chat_read(&input, chat.id, bob, ws_id).await?;
+        assert_eq!(read.last_read_message_id, Some(messages[1].id));
+        assert_eq!(read.unread_count, 1);
+
+        // the cursor doesn't go back
+        let input = MarkChatRead {
+            message_id: messages[0].id,
+        };
+        let read = state.mark_chat_read(&input, chat.id, bob, ws_id).await?;
+        assert_eq!(read.last_read_message_id, Some(messages[1].id));
+
+        let input = MarkChatRead {
+            message_id: pong.id + 100,
+        };
+        let ret = state.mark_chat_read(&input, chat.id, bob, ws_id).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        Ok(())
+    }
+}
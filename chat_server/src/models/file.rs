@@ -0,0 +1,522 @@
+use std::{str::FromStr, time::Duration};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    image::{self, ImageError, ImageFormat},
+    storage::Storage,
+    AppError, AppState,
+};
+
+/// A stored file, addressed by the hash of its content within a workspace, so
+/// uploading the same file twice keeps a single copy under the same url
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatFile {
+    pub ws_id: i64,
+    pub ext: String,
+    pub hash: String,
+}
+
+impl ChatFile {
+    pub fn new(ws_id: i64, filename: &str, data: &[u8]) -> Self {
+        Self {
+            ws_id,
+            ext: file_ext(filename),
+            hash: hex::encode(Sha256::digest(data)),
+        }
+    }
+
+    /// The url messages refer to the file by, served under /api
+    pub fn url(&self) -> String {
+        format!("/files/{}", self.key())
+    }
+
+    /// Where the file is kept in the storage, the hash is split so that no
+    /// directory gets too many entries
+    pub fn key(&self) -> String {
+        let (part1, rest) = self.hash.split_at(3);
+        let (part2, part3) = rest.split_at(3);
+        format!("{}/{}/{}/{}.{}", self.ws_id, part1, part2, part3, self.ext)
+    }
+
+    /// Content type to serve the file with, picked by extension
+    pub fn content_type(&self) -> &'static str {
+        match self.ext.as_str() {
+            "png" => "image/png",
+            "jpg" | "jpeg" => "image/jpeg",
+            "gif" => "image/gif",
+            "webp" => "image/webp",
+            "pdf" => "application/pdf",
+            "txt" => "text/plain; charset=utf-8",
+            _ => "application/octet-stream",
+        }
+    }
+}
+
+/// What to answer a file request with
+#[derive(Debug)]
+pub enum FileContent {
+    Data(Vec<u8>),
+    /// a presigned url of the storage backend
+    Redirect(String),
+}
+
+impl FromStr for ChatFile {
+    type Err = AppError;
+
+    /// Parse a url like /files/1/339/807/e635afbeab088ce33206fdf4223a6bb156.png
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || AppError::NotFound(format!("file {}", s));
+        let path = s.strip_prefix("/files/").ok_or_else(invalid)?;
+        let parts: Vec<&str> = path.split('/').collect();
+        let [ws_id, part1, part2, name] = parts[..] else {
+            return Err(invalid());
+        };
+        let (part3, ext) = name.split_once('.').ok_or_else(invalid)?;
+        let hash = format!("{}{}{}", part1, part2, part3);
+        let ws_id = ws_id.parse().map_err(|_| invalid())?;
+        // only hex and a plain extension, nothing that could point elsewhere in the storage
+        if part1.len() != 3
+            || part2.len() != 3
+            || hash.len() != 64
+            || !hash.chars().all(|c| c.is_ascii_hexdigit())
+            || ext != file_ext(&format!(".{}", ext))
+        {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            ws_id,
+            ext: ext.to_string(),
+            hash,
+        })
+    }
+}
+
+impl AppState {
+    /// Store an uploaded file of the workspace and record it as an attachment,
+    /// returns its url. Images have their location data removed first and get
+    /// a thumbnail when they are large
+    pub async fn save_file(
+        &self,
+        ws_id: i64,
+        uploader_id: i64,
+        filename: &str,
+        content_type: &str,
+        data: Vec<u8>,
+    ) -> Result<String, AppError> {
+        let config = &self.config.upload;
+        if !config.allowed_types.iter().any(|t| t == content_type) {
+            return Err(AppError::UnsupportedFileType(content_type.to_string()));
+        }
+        if data.len() > config.max_size {
+            return Err(AppError::FileTooLarge(config.max_size));
+        }
+
+        let (file, data, image) = match ImageFormat::from_mime(content_type) {
+            Some(format) => {
+                if ImageFormat::sniff(&data) != Some(format) {
+                    return Err(AppError::UploadError(format!(
+                        "{} is not {}",
+                        filename, content_type
+                    )));
+                }
+                let size = config.thumbnail_size;
+                // decoding is CPU bound, keep it off the async workers
+                let (data, info, thumbnail) = tokio::task::spawn_blocking(move || {
+                    let info = image::probe(&data)?;
+                    let data = image::strip_location(info.format, data)?;
+                    let thumbnail = image::thumbnail(&info, &data, size)?;
+                    Ok::<_, ImageError>((data, info, thumbnail))
+                })
+                .await
+                .map_err(|e| AppError::UploadError(e.to_string()))??;
+                // the extension follows the content, not the name the client sent
+                let file = ChatFile::new(ws_id, &format!(".{}", info.format.ext()), &data);
+                (file, data, Some((info, thumbnail)))
+            }
+            // a type served by extension, so it must come from the checked type as well
+            None => {
+                let file = ChatFile::new(ws_id, &format!(".{}", mime_ext(content_type)), &data);
+                (file, data, None)
+            }
+        };
+
+        let (info, thumbnail) = image.unzip();
+        let mut thumbnail_url = None;
+        if let Some(thumbnail) = thumbnail.flatten() {
+            let thumbnail_file = ChatFile::new(ws_id, ".png", &thumbnail);
+            self.store(&thumbnail_file, thumbnail).await?;
+            thumbnail_url = Some(thumbnail_file.url());
+        }
+        let size = data.len() as i64;
+        self.store(&file, data).await?;
+
+        let url = file.url();
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+                INSERT INTO attachments (ws_id,url,thumbnail_url,mime,size,width,height,uploader_id)
+                VALUES ($1,$2,$3,$4,$5,$6,$7,$8)
+                ON CONFLICT (url) DO NOTHING
+            "#,
+        )
+        .bind(ws_id)
+        .bind(&url)
+        .bind(thumbnail_url)
+        .bind(info.map_or(content_type, |i| i.format.mime()))
+        .bind(size)
+        .bind(info.map(|i| i.width as i32))
+        .bind(info.map(|i| i.height as i32))
+        .bind(uploader_id)
+        .execute(&mut *tx)
+        .await?;
+        // the attachment keeps the first uploader, every one of them counts
+        sqlx::query(
+            r#"
+                INSERT INTO attachment_uploaders (url,uploader_id)
+                VALUES ($1,$2)
+                ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(&url)
+        .bind(uploader_id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(url)
+    }
+
+    /// The first of the urls that is not a file of the workspace uploaded by the
+    /// sender or by a member of the chat, messages can't refer to any other
+    pub(super) async fn find_foreign_file(
+        &self,
+        urls: &[String],
+        chat_id: i64,
+        sender_id: i64,
+        ws_id: i64,
+    ) -> Result<Option<String>, AppError> {
+        if urls.is_empty() {
+            return Ok(None);
+        }
+        let foreign: Option<(String,)> = sqlx::query_as(
+            r#"
+                SELECT u.url FROM UNNEST($1::TEXT[]) WITH ORDINALITY AS u(url, n)
+                WHERE NOT EXISTS(
+                    SELECT 1 FROM attachments a
+                    JOIN attachment_uploaders au ON au.url = a.url
+                    WHERE a.url = u.url AND a.ws_id = $2 AND (
+                        au.uploader_id = $3
+                        OR au.uploader_id IN (SELECT user_id FROM chat_members WHERE chat_id = $4)
+                    )
+                )
+                ORDER BY u.n
+                LIMIT 1
+            "#,
+        )
+        .bind(urls)
+        .bind(ws_id)
+        .bind(sender_id)
+        .bind(chat_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(foreign.map(|(url,)| url))
+    }
+
+    async fn store(&self, file: &ChatFile, data: Vec<u8>) -> Result<(), AppError> {
+        let key = file.key();
+        if !self.storage.exists(&key).await? {
+            self.storage.put(&key, data, file.content_type()).await?;
+        }
+        Ok(())
+    }
+
+    /// Read a file for a user, who must have uploaded it or be in a chat with a
+    /// message referencing it or the image it is the thumbnail of.
+    /// Backends that can hand out their own download urls are not read from
+    pub async fn read_file(
+        &self,
+        url: &str,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<(ChatFile, FileContent), AppError> {
+        let file: ChatFile = url.parse()?;
+        if file.ws_id != ws_id || !self.can_read_file(url, user_id).await? {
+            return Err(AppError::NotFound(format!("file {}", url)));
+        }
+
+        let key = file.key();
+        let expires = Duration::from_secs(self.config.upload.presign_expires);
+        if let Some(url) = self.storage.presigned_url(&key, expires).await? {
+            return Ok((file, FileContent::Redirect(url)));
+        }
+        match self.storage.get(&key).await? {
+            Some(data) => Ok((file, FileContent::Data(data))),
+            None => Err(AppError::NotFound(format!("file {}", url))),
+        }
+    }
+
+    async fn can_read_file(&self, url: &str, user_id: i64) -> Result<bool, AppError> {
+        let (readable,): (bool,) = sqlx::query_as(
+            r#"
+                SELECT EXISTS(
+                    SELECT 1 FROM attachments a
+                    JOIN attachment_uploaders au ON au.url = a.url
+                    WHERE au.uploader_id = $1 AND (a.url = $2 OR a.thumbnail_url = $2)
+                ) OR EXISTS(
+                    SELECT 1 FROM messages m
+                    JOIN chat_members cm ON cm.chat_id = m.chat_id
+                    WHERE cm.user_id = $1 AND (
+                        $2 = ANY(m.images)
+                        -- or the thumbnail of an image the message refers to
+                        OR EXISTS(
+                            SELECT 1 FROM attachments a
+                            WHERE a.thumbnail_url = $2 AND a.url = ANY(m.images)
+                        )
+                    )
+                )
+            "#,
+        )
+        .bind(user_id)
+        .bind(url)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(readable)
+    }
+}
+
+/// Extension to store a file of a non-image type under, so that it is served
+/// back with the type it was checked against
+fn mime_ext(mime: &str) -> &'static str {
+    match mime {
+        "application/pdf" => "pdf",
+        "text/plain" => "txt",
+        _ => "bin",
+    }
+}
+
+/// Lowercase extension of a file name, `bin` when missing or unusual
+fn file_ext(filename: &str) -> String {
+    match filename.rsplit_once('.') {
+        Some((_, ext))
+            if !ext.is_empty()
+                && ext.len() <= 8
+                && ext.chars().all(|c| c.is_ascii_alphanumeric()) =>
+        {
+            ext.to_ascii_lowercase()
+        }
+        _ => "bin".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{CreateChat, CreateMessage, UpdateMessage},
+        AppConfig,
+    };
+    use anyhow::Result;
+    use chat_core::ChatType;
+
+    #[test]
+    fn chat_file_should_round_trip_through_url() -> Result<()> {
+        let file = ChatFile::new(1, "Cat.PNG", b"meow");
+        assert_eq!(file.ext, "png");
+        assert_eq!(file.content_type(), "image/png");
+
+        let url = file.url();
+        assert!(url.starts_with("/files/1/"));
+        assert_eq!(url.parse::<ChatFile>()?, file);
+        assert_eq!(format!("/files/{}", file.key()), url);
+
+        assert_eq!(ChatFile::new(1, "README", b"").ext, "bin");
+        assert_eq!(mime_ext("text/plain"), "txt");
+        assert_eq!(mime_ext("application/zip"), "bin");
+        assert!("/files/1/../../etc/passwd".parse::<ChatFile>().is_err());
+        assert!("/files/1/abc.png".parse::<ChatFile>().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn files_should_be_served_to_chat_members_only() -> Result<()> {
+        let (_guard, state, users) = AppState::new_for_test_with_users(AppConfig::load()?).await?;
+        let ws_id = users[0].ws_id;
+
+        let uploader = users[0].id;
+        let ret = state
+            .save_file(
+                ws_id,
+                uploader,
+                "x.exe",
+                "application/x-msdownload",
+                b"MZ".to_vec(),
+            )
+            .await;
+        assert!(matches!(ret, Err(AppError::UnsupportedFileType(_))));
+
+        let url = state
+            .save_file(ws_id, uploader, "cat.txt", "text/plain", b"meow".to_vec())
+            .await?;
+        // the same content is stored once
+        let again = state
+            .save_file(ws_id, uploader, "other.txt", "text/plain", b"meow".to_vec())
+            .await?;
+        assert_eq!(url, again);
+
+        // only the uploader can read it until a message refers to it
+        let (file, _) = state.read_file(&url, uploader, ws_id).await?;
+        assert_eq!(file.content_type(), "text/plain; charset=utf-8");
+        let ret = state.read_file(&url, users[1].id, ws_id).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        let input = CreateChat::new("pair", ChatType::Single, &[users[1].id]);
+        let chat = state.create_chat(&input, users[0].id, ws_id).await?;
+        let input = CreateMessage::new("look", &[&url]);
+        state
+            .create_message(&input, chat.id, users[0].id, ws_id)
+            .await?;
+
+        let (file, content) = state.read_file(&url, users[1].id, ws_id).await?;
+        assert_eq!(file.content_type(), "text/plain; charset=utf-8");
+        assert!(matches!(content, FileContent::Data(data) if data == b"meow"));
+        let ret = state.read_file(&url, users[2].id, ws_id).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn images_should_get_thumbnails_and_attachments() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.upload.thumbnail_size = 64;
+        let (_guard, state, users) = AppState::new_for_test_with_users(config).await?;
+        let (ws_id, uploader) = (users[0].ws_id, users[0].id);
+
+        // the content must be what the client says it is
+        let ret = state
+            .save_file(ws_id, uploader, "cat.png", "image/png", b"<svg/>".to_vec())
+            .await;
+        assert!(matches!(ret, Err(AppError::UploadError(_))));
+        // other types are named by the type they were accepted as as well
+        let text = state
+            .save_file(ws_id, uploader, "cat.png", "text/plain", b"meow".to_vec())
+            .await?;
+        assert!(text.ends_with(".txt"));
+        let (file, _) = state.read_file(&text, uploader, ws_id).await?;
+        assert_eq!(file.content_type(), "text/plain; charset=utf-8");
+        let data = image::solid_png(300, 100, [200, 10, 10, 255]);
+        let ret = state
+            .save_file(ws_id, uploader, "cat.jpg", "image/jpeg", data.clone())
+            .await;
+        assert!(matches!(ret, Err(AppError::UploadError(_))));
+
+        // named by content, not by the name sent
+        let large = state
+            .save_file(ws_id, uploader, "cat.gif", "image/png", data)
+            .await?;
+        assert!(large.ends_with(".png"));
+        let small = state
+            .save_file(
+                ws_id,
+                uploader,
+                "dot.png",
+                "image/png",
+                image::solid_png(10, 10, [0, 0, 0, 255]),
+            )
+            .await?;
+
+        let input = CreateChat::new("pair", ChatType::Single, &[users[1].id]);
+        let chat = state.create_chat(&input, users[0].id, ws_id).await?;
+        let input = CreateMessage::new("look", &[&large, &small]);
+        let message = state
+            .create_message(&input, chat.id, users[0].id, ws_id)
+            .await?;
+
+        let [a, b] = &message.attachments[..] else {
+            panic!("expected 2 attachments, got {:?}", message.attachments);
+        };
+        assert_eq!(a.url, large);
+        assert_eq!(
+            (a.mime.as_str(), a.width, a.height),
+            ("image/png", Some(300), Some(100))
+        );
+        assert_eq!(b.url, small);
+        assert_eq!(b.thumbnail_url, None);
+
+        // the thumbnail is readable by the members of the chat only
+        let thumbnail = a.thumbnail_url.as_deref().expect("should have a thumbnail");
+        let (_, content) = state.read_file(thumbnail, users[1].id, ws_id).await?;
+        let FileContent::Data(data) = content else {
+            panic!("expected data");
+        };
+        let info = image::probe(&data)?;
+        assert_eq!((info.width, info.height), (64, 21));
+        let ret = state.read_file(thumbnail, users[2].id, ws_id).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        let messages = state
+            .list_messages(&Default::default(), chat.id, users[1].id, ws_id)
+            .await?;
+        assert_eq!(messages[0].attachments, message.attachments);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn messages_should_only_refer_to_uploads_of_the_chat() -> Result<()> {
+        let (_guard, state, users) = AppState::new_for_test_with_users(AppConfig::load()?).await?;
+        let ws_id = users[0].ws_id;
+        let other = &state.create_test_users("globex", 1).await?[0];
+        let input = CreateChat::new("pair", ChatType::Single, &[users[1].id]);
+        let chat = state.create_chat(&input, users[0].id, ws_id).await?;
+
+        // someone outside the chat, or in another workspace
+        let secret = state
+            .save_file(ws_id, users[2].id, "a.txt", "text/plain", b"a".to_vec())
+            .await?;
+        let foreign = state
+            .save_file(other.ws_id, other.id, "b.txt", "text/plain", b"b".to_vec())
+            .await?;
+        for url in [&secret, &foreign, &"/files/1/abc.png".to_string()] {
+            let input = CreateMessage::new("look", &[url]);
+            let ret = state
+                .create_message(&input, chat.id, users[0].id, ws_id)
+                .await;
+            assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+        }
+
+        // uploaded by the other member
+        let url = state
+            .save_file(ws_id, users[1].id, "c.txt", "text/plain", b"c".to_vec())
+            .await?;
+        let input = CreateMessage::new("look", &[&url]);
+        let message = state
+            .create_message(&input, chat.id, users[0].id, ws_id)
+            .await?;
+
+        let input = UpdateMessage {
+            content: None,
+            images: Some(vec![url.clone(), secret.clone()]),
+        };
+        let ret = state
+            .update_message(&input, chat.id, message.id, users[0].id, ws_id)
+            .await;
+        assert!(matches!(ret, Err(AppError::UpdateMessageError(_))));
+
+        // the same content uploaded again counts for the new uploader
+        let again = state
+            .save_file(ws_id, users[0].id, "a.txt", "text/plain", b"a".to_vec())
+            .await?;
+        assert_eq!(again, secret);
+        let message = state
+            .update_message(&input, chat.id, message.id, users[0].id, ws_id)
+            .await?;
+        assert_eq!(message.images, vec![url, secret]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,621 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use chat_core::{ChatMemberRole, ChatType, Message, MessageEdit};
+
+use super::chat::pg_notify;
+use crate::{AppError, AppState};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 100;
+
+pub(super) const MESSAGE_COLUMNS: &str =
+    "id,chat_id,sender_id,content,COALESCE(images,'{}') AS images,\
+    created_at,parent_id,updated_at,deleted_at";
+
+const CHAT_MESSAGE_UPDATED: &str = "chat_message_updated";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateMessage {
+    pub content: String,
+    #[serde(default)]
+    pub images: Vec<String>,
+    /// reply in the thread of this message
+    #[serde(default)]
+    pub parent_id: Option<i64>,
+}
+
+#[cfg(test)]
+impl CreateMessage {
+    pub fn new(content: &str, images: &[&str]) -> Self {
+        Self {
+            content: content.to_string(),
+            images: images.iter().map(|s| s.to_string()).collect(),
+            parent_id: None,
+        }
+    }
+
+    pub fn reply(parent_id: i64, content: &str) -> Self {
+        Self {
+            parent_id: Some(parent_id),
+            ..Self::new(content, &[])
+        }
+    }
+}
+
+/// Fields left out are kept as they are
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UpdateMessage {
+    pub content: Option<String>,
+    pub images: Option<Vec<String>>,
+}
+
+/// Keyset pagination cursor, `last_id` is the oldest message id of the previous page
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ListMessages {
+    pub last_id: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+impl AppState {
+    /// Send a message to a chat the sender is a member of, its images must be
+    /// uploaded by the sender or another member
+    pub async fn create_message(
+        &self,
+        input: &CreateMessage,
+        chat_id: i64,
+        sender_id: i64,
+        ws_id: i64,
+    ) -> Result<Message, AppError> {
+        if input.content.is_empty() && input.images.is_empty() {
+            return Err(AppError::CreateMessageError(
+                "content and images cannot both be empty".to_string(),
+            ));
+        }
+
+        if !self.is_chat_member(chat_id, sender_id, ws_id).await? {
+            return Err(AppError::PermissionDenied(format!(
+                "user {} is not a member of chat {}",
+                sender_id, chat_id
+            )));
+        }
+        if let Some(parent_id) = input.parent_id {
+            match self.get_thread_parent(chat_id, parent_id).await? {
+                Some(false) => {}
+                Some(true) => {
+                    return Err(AppError::CreateMessageError(format!(
+                        "message id {} was deleted",
+                        parent_id
+                    )))
+                }
+                None => return Err(AppError::NotFound(format!("message id {}", parent_id))),
+            }
+        }
+        if let Some(url) = self
+            .find_foreign_file(&input.images, chat_id, sender_id, ws_id)
+            .await?
+        {
+            return Err(AppError::CreateMessageError(format!(
+                "image {} is not an upload of the chat",
+                url
+            )));
+        }
+
+        let mut message: Message = sqlx::query_as(&format!(
+            r#"
+                INSERT INTO messages (chat_id,sender_id,content,images,parent_id)
+                VALUES ($1,$2,$3,$4,$5)
+                RETURNING {}
+            "#,
+            MESSAGE_COLUMNS
+        ))
+        .bind(chat_id)
+        .bind(sender_id)
+        .bind(&input.content)
+        .bind(&input.images)
+        .bind(input.parent_id)
+        .fetch_one(&self.pool)
+        .await?;
+        Message::load_attachments(&self.pool, std::slice::from_mut(&mut message)).await?;
+
+        Ok(message)
+    }
+
+    /// List messages of a chat newest first, starting right before `last_id`.
+    /// Replies are left out, their count and latest time are on their parent
+    pub async fn list_messages(
+        &self,
+        input: &ListMessages,
+        chat_id: i64,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<Vec<Message>, AppError> {
+        if !self.is_chat_member(chat_id, user_id, ws_id).await? {
+            return Err(AppError::PermissionDenied(format!(
+                "user {} is not a member of chat {}",
+                user_id, chat_id
+            )));
+        }
+
+        let limit = input
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+
+        // (created_at, id) keeps the order stable for messages created at the same time,
+        // and walks chat_id_created_at_index instead of scanning with an offset
+        let mut messages: Vec<Message> = sqlx::query_as(&format!(
+            r#"
+                SELECT {},r.reply_count,r.last_reply_at
+                FROM messages
+                LEFT JOIN LATERAL (
+                    SELECT COUNT(*) AS reply_count, MAX(created_at) AS last_reply_at
+                    FROM messages r
+                    WHERE r.parent_id = messages.id AND r.deleted_at IS NULL
+                ) r ON TRUE
+                WHERE chat_id = $1 AND parent_id IS NULL
+                AND ($2::BIGINT IS NULL OR (created_at, id) < (
+                    SELECT created_at, id FROM messages WHERE id = $2 AND chat_id = $1
+                ))
+                ORDER BY created_at DESC, id DESC
+                LIMIT $3
+            "#,
+            MESSAGE_COLUMNS
+        ))
+        .bind(chat_id)
+        .bind(input.last_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Message::load_attachments(&self.pool, &mut messages).await?;
+        self.load_reactions(&mut messages, user_id).await?;
+
+        Ok(messages)
+    }
+
+    /// List the replies to a message oldest first, starting right after `last_id`
+    pub async fn list_replies(
+        &self,
+        input: &ListMessages,
+        chat_id: i64,
+        parent_id: i64,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<Vec<Message>, AppError> {
+        if !self.is_chat_member(chat_id, user_id, ws_id).await? {
+            return Err(AppError::PermissionDenied(format!(
+                "user {} is not a member of chat {}",
+                user_id, chat_id
+            )));
+        }
+        // the thread of a deleted message can still be read
+        if self.get_thread_parent(chat_id, parent_id).await?.is_none() {
+            return Err(AppError::NotFound(format!("message id {}", parent_id)));
+        }
+
+        let limit = input
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        let mut messages: Vec<Message> = sqlx::query_as(&format!(
+            r#"
+                SELECT {}
+                FROM messages
+                WHERE parent_id = $1
+                AND ($2::BIGINT IS NULL OR (created_at, id) > (
+                    SELECT created_at, id FROM messages WHERE id = $2 AND parent_id = $1
+                ))
+                ORDER BY created_at, id
+                LIMIT $3
+            "#,
+            MESSAGE_COLUMNS
+        ))
+        .bind(parent_id)
+        .bind(input.last_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Message::load_attachments(&self.pool, &mut messages).await?;
+        self.load_reactions(&mut messages, user_id).await?;
+
+        Ok(messages)
+    }
+
+    /// Edit a message, only its sender can. The previous version goes to the history
+    pub async fn update_message(
+        &self,
+        input: &UpdateMessage,
+        chat_id: i64,
+        id: i64,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<Message, AppError> {
+        let old = self.get_message(chat_id, id, user_id, ws_id).await?;
+        if old.sender_id != user_id {
+            return Err(AppError::PermissionDenied(format!(
+                "message id {} was sent by another user",
+                id
+            )));
+        }
+
+        let content = input.content.as_ref().unwrap_or(&old.content);
+        let images = input.images.as_ref().unwrap_or(&old.images);
+        if content.is_empty() && images.is_empty() {
+            return Err(AppError::UpdateMessageError(
+                "content and images cannot both be empty".to_string(),
+            ));
+        }
+        if *content == old.content && *images == old.images {
+            return Ok(old);
+        }
+        // images the message had already are kept as they are
+        let added: Vec<String> = images
+            .iter()
+            .filter(|url| !old.images.contains(url))
+            .cloned()
+            .collect();
+        if let Some(url) = self
+            .find_foreign_file(&added, chat_id, user_id, ws_id)
+            .await?
+        {
+            return Err(AppError::UpdateMessageError(format!(
+                "image {} is not an upload of the chat",
+                url
+            )));
+        }
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+                INSERT INTO message_edits (message_id,content,images)
+                SELECT id,content,COALESCE(images,'{}') FROM messages WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+        let mut message: Message = sqlx::query_as(&format!(
+            r#"
+                UPDATE messages SET content = $2, images = $3, updated_at = NOW()
+                WHERE id = $1
+                RETURNING {}
+            "#,
+            MESSAGE_COLUMNS
+        ))
+        .bind(id)
+        .bind(content)
+        .bind(images)
+        .fetch_one(&mut *tx)
+        .await?;
+        notify_message_updated(&mut tx, "UPDATE", &message).await?;
+        tx.commit().await?;
+        let messages = std::slice::from_mut(&mut message);
+        Message::load_attachments(&self.pool, messages).await?;
+        self.load_reactions(messages, user_id).await?;
+
+        Ok(message)
+    }
+
+    /// Delete a message, by its sender or an owner or admin of a group or channel.
+    /// The message stays in place with no content, its history and reactions are dropped
+    pub async fn delete_message(
+        &self,
+        chat_id: i64,
+        id: i64,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<(), AppError> {
+        let message = self.get_message(chat_id, id, user_id, ws_id).await?;
+        if message.sender_id != user_id {
+            let (chat, member) = self.get_member_chat(chat_id, user_id, ws_id).await?;
+            if chat.r#type == ChatType::Single || member.role == ChatMemberRole::Member {
+                return Err(AppError::PermissionDenied(format!(
+                    "message id {} was sent by another user",
+                    id
+                )));
+            }
+        }
+
+        let mut tx = self.pool.begin().await?;
+        for table in ["message_edits", "message_reactions"] {
+            sqlx::query(&format!("DELETE FROM {} WHERE message_id = $1", table))
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        let message: Message = sqlx::query_as(&format!(
+            r#"
+                UPDATE messages SET content = '', images = '{{}}', deleted_at = NOW()
+                WHERE id = $1
+                RETURNING {}
+            "#,
+            MESSAGE_COLUMNS
+        ))
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+        notify_message_updated(&mut tx, "DELETE", &message).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// The previous versions of a message, oldest first
+    pub async fn list_message_edits(
+        &self,
+        chat_id: i64,
+        id: i64,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<Vec<MessageEdit>, AppError> {
+        self.get_message(chat_id, id, user_id, ws_id).await?;
+        let edits = sqlx::query_as(
+            r#"
+                SELECT message_id,content,images,edited_at
+                FROM message_edits
+                WHERE message_id = $1
+                ORDER BY id
+            "#,
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(edits)
+    }
+
+    /// Whether a message of the chat that can have a thread is deleted, `None` if
+    /// there is no such message. Replies have no thread of their own
+    async fn get_thread_parent(&self, chat_id: i64, id: i64) -> Result<Option<bool>, AppError> {
+        let parent: Option<(bool,)> = sqlx::query_as(
+            r#"
+                SELECT deleted_at IS NOT NULL FROM messages
+                WHERE id = $1 AND chat_id = $2 AND parent_id IS NULL
+            "#,
+        )
+        .bind(id)
+        .bind(chat_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(parent.map(|(deleted,)| deleted))
+    }
+
+    /// A message of a chat the user is a member of, deleted messages are not found
+    pub(super) async fn get_message(
+        &self,
+        chat_id: i64,
+        id: i64,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<Message, AppError> {
+        if !self.is_chat_member(chat_id, user_id, ws_id).await? {
+            return Err(AppError::PermissionDenied(format!(
+                "user {} is not a member of chat {}",
+                user_id, chat_id
+            )));
+        }
+
+        let message: Option<Message> = sqlx::query_as(&format!(
+            r#"
+                SELECT {}
+                FROM messages
+                WHERE id = $1 AND chat_id = $2 AND deleted_at IS NULL
+            "#,
+            MESSAGE_COLUMNS
+        ))
+        .bind(id)
+        .bind(chat_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        message.ok_or_else(|| AppError::NotFound(format!("message id {}", id)))
+    }
+}
+
+/// Tell notify_server about an edited or deleted message. Like new messages only
+/// the id is sent, the payload of pg_notify is limited to 8000 bytes. notify_server
+/// loads the message and its recipients
+async fn notify_message_updated(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    op: &str,
+    message: &Message,
+) -> Result<(), AppError> {
+    let payload = json!({ "op": op, "id": message.id });
+    pg_notify(tx, CHAT_MESSAGE_UPDATED, &payload).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::CreateChat, AppConfig};
+    use anyhow::Result;
+    use chat_core::ChatType;
+
+    #[tokio::test]
+    async fn create_and_list_messages_should_work() -> Result<()> {
+        let (_guard, state, users) = AppState::new_for_test_with_users(AppConfig::load()?).await?;
+        let (alice, bob) = (&users[0], &users[1]);
+        let ws_id = alice.ws_id;
+        let input = CreateChat::new("alice-bob", ChatType::Single, &[bob.id]);
+        let chat = state.create_chat(&input, alice.id, ws_id).await?;
+
+        for i in 0..5 {
+            let input = CreateMessage::new(&format!("hello {}", i), &[]);
+            state
+                .create_message(&input, chat.id, alice.id, ws_id)
+                .await?;
+        }
+        let url = state
+            .save_file(ws_id, bob.id, "hi.txt", "text/plain", b"hi".to_vec())
+            .await?;
+        let input = CreateMessage::new("", &[&url]);
+        let last = state.create_message(&input, chat.id, bob.id, ws_id).await?;
+        assert_eq!(last.images, vec![url]);
+
+        let input = ListMessages {
+            last_id: None,
+            limit: Some(4),
+        };
+        let page = state.list_messages(&input, chat.id, bob.id, ws_id).await?;
+        assert_eq!(page.len(), 4);
+        assert_eq!(page[0].id, last.id);
+
+        let input = ListMessages {
+            last_id: page.last().map(|m| m.id),
+            limit: Some(4),
+        };
+        let next = state.list_messages(&input, chat.id, bob.id, ws_id).await?;
+        assert_eq!(next.len(), 2);
+        assert_eq!(next[0].content, "hello 1");
+        assert_eq!(next[1].content, "hello 0");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn non_member_should_not_send_or_list_messages() -> Result<()> {
+        let (_guard, state, users) = AppState::new_for_test_with_users(AppConfig::load()?).await?;
+        let (alice, eve) = (&users[0], &users[3]);
+        let ws_id = alice.ws_id;
+        let input = CreateChat::new("notes", ChatType::PrivateChannel, &[]);
+        let chat = state.create_chat(&input, alice.id, ws_id).await?;
+
+        let input = CreateMessage::new("hi", &[]);
+        let ret = state.create_message(&input, chat.id, eve.id, ws_id).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let ret = state
+            .list_messages(&ListMessages::default(), chat.id, eve.id, ws_id)
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn messages_should_be_edited_by_sender_and_deleted_by_admins() -> Result<()> {
+        let (_guard, state, users) = AppState::new_for_test_with_users(AppConfig::load()?).await?;
+        let ws_id = users[0].ws_id;
+        let (alice, bob, charlie) = (users[0].id, users[1].id, users[2].id);
+        let input = CreateChat::new("team", ChatType::Group, &[bob, charlie]);
+        let chat = state.create_chat(&input, alice, ws_id).await?;
+
+        let input = CreateMessage::new("helo", &[]);
+        let message = state.create_message(&input, chat.id, bob, ws_id).await?;
+        let mid = message.id;
+
+        // only the sender edits
+        let input = UpdateMessage {
+            content: Some("hello".to_string()),
+            images: None,
+        };
+        let ret = state
+            .update_message(&input, chat.id, mid, alice, ws_id)
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let edited = state
+            .update_message(&input, chat.id, mid, bob, ws_id)
+            .await?;
+        assert_eq!(edited.content, "hello");
+        assert!(edited.updated_at.is_some());
+
+        let input = UpdateMessage {
+            content: Some(String::new()),
+            images: None,
+        };
+        let ret = state.update_message(&input, chat.id, mid, bob, ws_id).await;
+        assert!(matches!(ret, Err(AppError::UpdateMessageError(_))));
+
+        let edits = state
+            .list_message_edits(chat.id, mid, charlie, ws_id)
+            .await?;
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].content, "helo");
+
+        // a plain member can't delete the messages of others, the owner can
+        let ret = state.delete_message(chat.id, mid, charlie, ws_id).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        state.delete_message(chat.id, mid, alice, ws_id).await?;
+
+        let messages = state
+            .list_messages(&ListMessages::default(), chat.id, charlie, ws_id)
+            .await?;
+        assert_eq!(messages[0].id, mid);
+        assert!(messages[0].deleted_at.is_some());
+        assert_eq!(messages[0].content, "");
+
+        // a deleted message can't be changed, and its history is gone
+        let ret = state.delete_message(chat.id, mid, bob, ws_id).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        let ret = state.list_message_edits(chat.id, mid, bob, ws_id).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        let edits: (i64,) =
+            sqlx::query_as(r#"SELECT COUNT(*) FROM message_edits WHERE message_id = $1"#)
+                .bind(mid)
+                .fetch_one(&state.pool)
+                .await?;
+        assert_eq!(edits.0, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replies_should_form_threads() -> Result<()> {
+        let (_guard, state, users) = AppState::new_for_test_with_users(AppConfig::load()?).await?;
+        let ws_id = users[0].ws_id;
+        let (alice, bob, charlie) = (users[0].id, users[1].id, users[2].id);
+        let input = CreateChat::new("team", ChatType::Group, &[bob, charlie]);
+        let chat = state.create_chat(&input, alice, ws_id).await?;
+
+        let input = CreateMessage::new("lunch?", &[]);
+        let parent = state.create_message(&input, chat.id, alice, ws_id).await?;
+        let mut replies = Vec::new();
+        for i in 0..3 {
+            let input = CreateMessage::reply(parent.id, &format!("sure {}", i));
+            replies.push(state.create_message(&input, chat.id, bob, ws_id).await?);
+        }
+        assert_eq!(replies[0].parent_id, Some(parent.id));
+
+        // one level deep only
+        let input = CreateMessage::reply(replies[0].id, "me too");
+        let ret = state.create_message(&input, chat.id, charlie, ws_id).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        // replies stay in their thread, the parent counts them
+        let messages = state
+            .list_messages(&ListMessages::default(), chat.id, charlie, ws_id)
+            .await?;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].reply_count, 3);
+        assert_eq!(messages[0].last_reply_at, Some(replies[2].created_at));
+
+        let input = ListMessages {
+            last_id: None,
+            limit: Some(2),
+        };
+        let page = state
+            .list_replies(&input, chat.id, parent.id, charlie, ws_id)
+            .await?;
+        assert_eq!(page, replies[..2]);
+        let input = ListMessages {
+            last_id: Some(page[1].id),
+            limit: Some(2),
+        };
+        let page = state
+            .list_replies(&input, chat.id, parent.id, charlie, ws_id)
+            .await?;
+        assert_eq!(page, replies[2..]);
+
+        // charlie hasn't taken part in the thread yet
+        let (mut recipients,): (Vec<i64>,) = sqlx::query_as(r#"SELECT message_recipients($1, $2)"#)
+            .bind(chat.id)
+            .bind(parent.id)
+            .fetch_one(&state.pool)
+            .await?;
+        recipients.sort_unstable();
+        assert_eq!(recipients, vec![alice, bob]);
+
+        Ok(())
+    }
+}
@@ -1,29 +1,21 @@
+mod channel;
+mod chat;
+mod chat_member;
+mod chat_read;
+mod file;
+mod message;
+mod reaction;
+mod session;
+mod typing;
 mod user;
+mod workspace;
 
-use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
-use sqlx::prelude::*;
-pub use user::{CreateUser, SigninUser};
-#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
-pub struct User {
-    pub id: i64,
-    pub fullname: String,
-    pub email: String,
-    #[serde(skip)]
-    #[sqlx(default)]
-    pub password_hash: Option<String>,
-    pub created_at: DateTime<Utc>,
-}
-
-#[cfg(test)]
-impl User {
-    pub fn new(id: i64, fullname: &str, email: &str) -> Self {
-        Self {
-            id,
-            fullname: fullname.to_string(),
-            email: email.to_string(),
-            password_hash: None,
-            created_at: Utc::now(),
-        }
-    }
-}
+pub use channel::ListChannels;
+pub use chat::{CreateChat, UpdateChat};
+pub use chat_member::{AddChatMembers, UpdateChatMember};
+pub use chat_read::MarkChatRead;
+pub use file::FileContent;
+pub use message::{CreateMessage, ListMessages, UpdateMessage};
+pub use reaction::AddReaction;
+pub use session::{AuthOutput, RefreshInput};
+pub use user::{ChangePassword, CreateUser, SigninUser};
@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use chat_core::{Message, Reaction};
+
+use super::chat::pg_notify;
+use crate::{AppError, AppState};
+
+const MESSAGE_REACTION_UPDATED: &str = "message_reaction_updated";
+
+/// longest emoji, in chars, enough for ZWJ sequences and :shortcodes:
+const MAX_EMOJI_LEN: usize = 32;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AddReaction {
+    pub emoji: String,
+}
+
+impl AppState {
+    /// React to a message with an emoji, returns the reactions of the message.
+    /// Reacting twice with the same emoji changes nothing
+    pub async fn add_reaction(
+        &self,
+        input: &AddReaction,
+        chat_id: i64,
+        message_id: i64,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<Vec<Reaction>, AppError> {
+        validate_emoji(&input.emoji)?;
+        let mut message = self
+            .get_message(chat_id, message_id, user_id, ws_id)
+            .await?;
+
+        let mut tx = self.pool.begin().await?;
+        let ret = sqlx::query(
+            r#"
+                INSERT INTO message_reactions (message_id,user_id,emoji)
+                VALUES ($1,$2,$3)
+                ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(message_id)
+        .bind(user_id)
+        .bind(&input.emoji)
+        .execute(&mut *tx)
+        .await?;
+        if ret.rows_affected() > 0 {
+            notify_reaction_updated(&mut tx, "ADD", &message, &input.emoji, user_id).await?;
+        }
+        tx.commit().await?;
+
+        self.load_reactions(std::slice::from_mut(&mut message), user_id)
+            .await?;
+        Ok(message.reactions)
+    }
+
+    /// Take back a reaction, returns the reactions of the message
+    pub async fn remove_reaction(
+        &self,
+        emoji: &str,
+        chat_id: i64,
+        message_id: i64,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<Vec<Reaction>, AppError> {
+        let mut message = self
+            .get_message(chat_id, message_id, user_id, ws_id)
+            .await?;
+
+        let mut tx = self.pool.begin().await?;
+        let ret = sqlx::query(
+            r#"DELETE FROM message_reactions WHERE message_id = $1 AND user_id = $2 AND emoji = $3"#,
+        )
+        .bind(message_id)
+        .bind(user_id)
+        .bind(emoji)
+        .execute(&mut *tx)
+        .await?;
+        if ret.rows_affected() > 0 {
+            notify_reaction_updated(&mut tx, "REMOVE", &message, emoji, user_id).await?;
+        }
+        tx.commit().await?;
+
+        self.load_reactions(std::slice::from_mut(&mut message), user_id)
+            .await?;
+        Ok(message.reactions)
+    }
+
+    /// Fill in the reactions of messages as seen by the user
+    pub(super) async fn load_reactions(
+        &self,
+        messages: &mut [Message],
+        user_id: i64,
+    ) -> Result<(), AppError> {
+        let ids: Vec<i64> = messages.iter().map(|m| m.id).collect();
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        let rows: Vec<(i64, String, i64, bool)> = sqlx::query_as(
+            r#"
+                SELECT message_id,emoji,COUNT(*),BOOL_OR(user_id = $2)
+                FROM message_reactions
+                WHERE message_id = ANY($1)
+                GROUP BY message_id,emoji
+                ORDER BY MIN(created_at),emoji
+            "#,
+        )
+        .bind(&ids)
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut reactions: HashMap<i64, Vec<Reaction>> = HashMap::new();
+        for (message_id, emoji, count, me) in rows {
+            reactions
+                .entry(message_id)
+                .or_default()
+                .push(Reaction { emoji, count, me });
+        }
+        for message in messages.iter_mut() {
+            message.reactions = reactions.remove(&message.id).unwrap_or_default();
+        }
+        Ok(())
+    }
+}
+
+fn validate_emoji(emoji: &str) -> Result<(), AppError> {
+    let len = emoji.chars().count();
+    if len == 0 || len > MAX_EMOJI_LEN || emoji.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        return Err(AppError::ReactionError(format!(
+            "invalid emoji {:?}",
+            emoji
+        )));
+    }
+    Ok(())
+}
+
+/// Tell the users who see the message about a reaction, with the new count of the emoji
+async fn notify_reaction_updated(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    op: &str,
+    message: &Message,
+    emoji: &str,
+    user_id: i64,
+) -> Result<(), AppError> {
+    let (count,): (i64,) = sqlx::query_as(
+        r#"SELECT COUNT(*) FROM message_reactions WHERE message_id = $1 AND emoji = $2"#,
+    )
+    .bind(message.id)
+    .bind(emoji)
+    .fetch_one(&mut **tx)
+    .await?;
+    let payload = json!({
+        "op": op,
+        "chat_id": message.chat_id,
+        "message_id": message.id,
+        "emoji": emoji,
+        "user_id": user_id,
+        "count": count,
+        // notify_server finds the recipients, the thread participants for a reply
+        "parent_id": message.parent_id,
+    });
+    pg_notify(tx, MESSAGE_REACTION_UPDATED, &payload).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{CreateChat, CreateMessage, ListMessages},
+        AppConfig,
+    };
+    use anyhow::Result;
+    use chat_core::ChatType;
+
+    #[test]
+    fn emoji_should_be_short_and_without_spaces() {
+        assert!(validate_emoji("👍").is_ok());
+        assert!(validate_emoji("👩‍👩‍👧‍👦").is_ok());
+        assert!(validate_emoji(":party_parrot:").is_ok());
+        assert!(validate_emoji("").is_err());
+        assert!(validate_emoji("a b").is_err());
+        assert!(validate_emoji(&"x".repeat(33)).is_err());
+    }
+
+    #[tokio::test]
+    async fn reactions_should_be_counted_per_emoji() -> Result<()> {
+        let (_guard, state, users) = AppState::new_for_test_with_users(AppConfig::load()?).await?;
+        let ws_id = users[0].ws_id;
+        let (alice, bob, charlie) = (users[0].id, users[1].id, users[2].id);
+        let input = CreateChat::new("pair", ChatType::Single, &[bob]);
+        let chat = state.create_chat(&input, alice, ws_id).await?;
+        let input = CreateMessage::new("shipped!", &[]);
+        let message = state.create_message(&input, chat.id, alice, ws_id).await?;
+
+        let react = |user_id, emoji: &str| {
+            let input = AddReaction {
+                emoji: emoji.to_string(),
+            };
+            let state = state.clone();
+            async move {
+                state
+                    .add_reaction(&input, chat.id, message.id, user_id, ws_id)
+                    .await
+            }
+        };
+        react(alice, "🎉").await?;
+        react(bob, "🎉").await?;
+        // the same reaction again changes nothing
+        react(bob, "🎉").await?;
+        let reactions = react(bob, "🚀").await?;
+        assert_eq!(
+            reactions,
+            vec![
+                Reaction {
+                    emoji: "🎉".to_string(),
+                    count: 2,
+                    me: true
+                },
+                Reaction {
+                    emoji: "🚀".to_string(),
+                    count: 1,
+                    me: true
+                },
+            ]
+        );
+
+        // non members can't react
+        let ret = react(charlie, "👀").await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let reactions = state
+            .remove_reaction("🎉", chat.id, message.id, bob, ws_id)
+            .await?;
+        assert_eq!(reactions[0].count, 1);
+        assert!(!reactions[0].me);
+
+        let messages = state
+            .list_messages(&ListMessages::default(), chat.id, alice, ws_id)
+            .await?;
+        let seen: Vec<_> = messages[0]
+            .reactions
+            .iter()
+            .map(|r| (r.emoji.as_str(), r.count, r.me))
+            .collect();
+        assert_eq!(seen, [("🎉", 1, true), ("🚀", 1, false)]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,320 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::FromRow;
+
+use chat_core::{CoreError, User, UserClaims};
+
+use crate::{AppError, AppState};
+
+const REFRESH_TOKEN_DURATION: i64 = 60 * 60 * 24 * 30;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuthOutput {
+    pub token: String,
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshInput {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, FromRow)]
+struct RefreshTokenRow {
+    id: i64,
+    session_id: i64,
+    user_id: i64,
+    ws_id: i64,
+    expires_at: DateTime<Utc>,
+    used_at: Option<DateTime<Utc>>,
+    revoked_at: Option<DateTime<Utc>>,
+}
+
+impl AppState {
+    /// Start a new session for a signed in user and issue its first tokens
+    pub async fn create_session(&self, user: &User) -> Result<AuthOutput, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let (sid,): (i64,) =
+            sqlx::query_as(r#"INSERT INTO sessions (user_id) VALUES ($1) RETURNING id"#)
+                .bind(user.id)
+                .fetch_one(&mut *tx)
+                .await?;
+        let refresh_token = insert_refresh_token(&mut tx, sid).await?;
+        tx.commit().await?;
+
+        let token = self.ek.sign(UserClaims {
+            uid: user.id,
+            wid: user.ws_id,
+            sid,
+        })?;
+        Ok(AuthOutput {
+            token,
+            refresh_token,
+        })
+    }
+
+    /// Exchange a refresh token for a new access token and a new refresh token.
+    /// Each refresh token can be used only once, presenting a used one means it
+    /// was leaked, so the whole session is revoked
+    pub async fn refresh_session(&self, refresh_token: &str) -> Result<AuthOutput, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let row: Option<RefreshTokenRow> = sqlx::query_as(
+            r#"
+                SELECT r.id,r.session_id,s.user_id,u.ws_id,r.expires_at,r.used_at,s.revoked_at
+                FROM refresh_tokens r
+                JOIN sessions s ON s.id = r.session_id
+                JOIN users u ON u.id = s.user_id
+                WHERE r.token_hash = $1
+                FOR UPDATE OF r
+            "#,
+        )
+        .bind(hash_token(refresh_token))
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(row) = row else {
+            return Err(invalid_refresh_token("unknown refresh token"));
+        };
+        if row.revoked_at.is_some() {
+            return Err(invalid_refresh_token("session revoked"));
+        }
+        if row.used_at.is_some() {
+            revoke_session(&mut tx, row.session_id).await?;
+            tx.commit().await?;
+            tracing::warn!("refresh token reused, session {} revoked", row.session_id);
+            return Err(invalid_refresh_token("refresh token reused"));
+        }
+        if row.expires_at < Utc::now() {
+            return Err(invalid_refresh_token("refresh token expired"));
+        }
+
+        sqlx::query(r#"UPDATE refresh_tokens SET used_at = NOW() WHERE id = $1"#)
+            .bind(row.id)
+            .execute(&mut *tx)
+            .await?;
+        let refresh_token = insert_refresh_token(&mut tx, row.session_id).await?;
+        tx.commit().await?;
+
+        let token = self.ek.sign(UserClaims {
+            uid: row.user_id,
+            wid: row.ws_id,
+            sid: row.session_id,
+        })?;
+        Ok(AuthOutput {
+            token,
+            refresh_token,
+        })
+    }
+
+    /// Revoke the session a refresh token belongs to, unknown tokens are ignored
+    pub async fn signout(&self, refresh_token: &str) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+                UPDATE sessions SET revoked_at = NOW()
+                WHERE revoked_at IS NULL
+                AND id = (SELECT session_id FROM refresh_tokens WHERE token_hash = $1)
+            "#,
+        )
+        .bind(hash_token(refresh_token))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+async fn insert_refresh_token(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    sid: i64,
+) -> Result<String, AppError> {
+    let token = generate_token();
+    let expires_at = Utc::now() + Duration::seconds(REFRESH_TOKEN_DURATION);
+    sqlx::query(
+        r#"INSERT INTO refresh_tokens (session_id,token_hash,expires_at) VALUES ($1,$2,$3)"#,
+    )
+    .bind(sid)
+    .bind(hash_token(&token))
+    .bind(expires_at)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(token)
+}
+
+async fn revoke_session(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    sid: i64,
+) -> Result<(), AppError> {
+    sqlx::query(r#"UPDATE sessions SET revoked_at = NOW() WHERE id = $1"#)
+        .bind(sid)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+/// Sign out every device of the user, e.g. after the password changed
+pub(super) async fn revoke_user_sessions(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    user_id: i64,
+) -> Result<(), AppError> {
+    sqlx::query(
+        r#"UPDATE sessions SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL"#,
+    )
+    .bind(user_id)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+fn invalid_refresh_token(msg: &str) -> AppError {
+    AppError::CoreError(CoreError::InvalidToken(msg.to_string()))
+}
+
+pub(super) fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+pub(super) fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{ChangePassword, CreateUser, SigninUser},
+        AppConfig,
+    };
+    use anyhow::Result;
+    use chat_core::TokenVerify;
+
+    #[test]
+    fn generate_and_hash_token_should_work() {
+        let token = generate_token();
+        assert_eq!(token.len(), 64);
+        assert_ne!(token, generate_token());
+        assert_eq!(hash_token(&token).len(), 64);
+        assert_eq!(hash_token(&token), hash_token(&token));
+    }
+
+    #[tokio::test]
+    async fn refresh_session_should_rotate_tokens() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let input = CreateUser::new("acme", "Alice", "alice@acme.org", "alice1988");
+        let user = state.create_user(&input).await?;
+
+        let first = state.create_session(&user).await?;
+        assert!(state.verify(&first.token).await.is_ok());
+
+        let second = state.refresh_session(&first.refresh_token).await?;
+        assert_ne!(second.refresh_token, first.refresh_token);
+        assert!(state.verify(&second.token).await.is_ok());
+
+        let third = state.refresh_session(&second.refresh_token).await?;
+        assert!(state.verify(&third.token).await.is_ok());
+
+        let ret = state.refresh_session("not-a-token").await;
+        assert!(matches!(ret, Err(AppError::CoreError(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reused_refresh_token_should_revoke_session() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let input = CreateUser::new("acme", "Alice", "alice@acme.org", "alice1988");
+        let user = state.create_user(&input).await?;
+
+        let first = state.create_session(&user).await?;
+        let second = state.refresh_session(&first.refresh_token).await?;
+
+        let ret = state.refresh_session(&first.refresh_token).await;
+        assert!(matches!(ret, Err(AppError::CoreError(_))));
+        // the legitimate holder is signed out as well
+        let ret = state.refresh_session(&second.refresh_token).await;
+        assert!(matches!(ret, Err(AppError::CoreError(_))));
+        assert!(state.verify(&second.token).await.is_err());
+
+        // other sessions of the user are not affected
+        let other = state.create_session(&user).await?;
+        assert!(state.verify(&other.token).await.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signout_should_revoke_session() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let input = CreateUser::new("acme", "Alice", "alice@acme.org", "alice1988");
+        let user = state.create_user(&input).await?;
+
+        let output = state.create_session(&user).await?;
+        state.signout(&output.refresh_token).await?;
+
+        assert!(state.verify(&output.token).await.is_err());
+        let ret = state.refresh_session(&output.refresh_token).await;
+        assert!(matches!(ret, Err(AppError::CoreError(_))));
+        // signing out twice is fine
+        state.signout(&output.refresh_token).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn change_password_should_revoke_all_sessions() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let input = CreateUser::new("acme", "Alice", "alice@acme.org", "alice1988");
+        let user = state.create_user(&input).await?;
+        let phone = state.create_session(&user).await?;
+        let laptop = state.create_session(&user).await?;
+
+        let input = ChangePassword {
+            old_password: "wrong".to_string(),
+            new_password: "alice1989".to_string(),
+        };
+        let ret = state.change_password(&user, &input).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        assert!(state.verify(&phone.token).await.is_ok());
+
+        let input = ChangePassword {
+            old_password: "alice1988".to_string(),
+            new_password: "alice1989".to_string(),
+        };
+        let output = state.change_password(&user, &input).await?;
+        assert!(state.verify(&output.token).await.is_ok());
+        for old in [&phone, &laptop] {
+            assert!(state.verify(&old.token).await.is_err());
+            assert!(state.refresh_session(&old.refresh_token).await.is_err());
+        }
+        let signin = SigninUser::new("alice@acme.org", "alice1989");
+        assert!(state.verify_user(&signin).await?.is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_should_load_current_user() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+        let input = CreateUser::new("acme", "Alice", "alice@acme.org", "alice1988");
+        let user = state.create_user(&input).await?;
+        let output = state.create_session(&user).await?;
+        assert_eq!(state.verify(&output.token).await?.fullname, "Alice");
+
+        sqlx::query("UPDATE users SET fullname = 'Alicia' WHERE id = $1")
+            .bind(user.id)
+            .execute(&state.pool)
+            .await?;
+        state.user_cache.invalidate(user.id);
+        let user = state.verify(&output.token).await?;
+        assert_eq!(user.fullname, "Alicia");
+        assert_eq!(user.email, "alice@acme.org");
+
+        Ok(())
+    }
+}
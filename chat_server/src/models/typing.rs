@@ -0,0 +1,59 @@
+use chat_core::Typing;
+
+use crate::{AppError, AppState};
+
+impl AppState {
+    /// Tell the other members of a chat the user is typing. Nothing is stored,
+    /// the event goes straight to notify_server and is lost if nobody listens
+    pub async fn send_typing(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<(), AppError> {
+        // checked first, a flood of typing events shouldn't reach the database
+        if !self.typing_limiter.check(user_id) {
+            return Err(AppError::TooManyRequests(format!(
+                "user {} is typing too fast",
+                user_id
+            )));
+        }
+        if !self.is_chat_member(chat_id, user_id, ws_id).await? {
+            return Err(AppError::PermissionDenied(format!(
+                "user {} is not a member of chat {}",
+                user_id, chat_id
+            )));
+        }
+
+        Typing { chat_id, user_id }.notify(&self.pool).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::CreateChat, AppConfig};
+    use anyhow::Result;
+    use chat_core::ChatType;
+
+    #[tokio::test]
+    async fn typing_should_be_rate_limited_per_user() -> Result<()> {
+        let (_guard, state, users) = AppState::new_for_test_with_users(AppConfig::load()?).await?;
+        let ws_id = users[0].ws_id;
+        let (alice, bob, eve) = (users[0].id, users[1].id, users[2].id);
+        let input = CreateChat::new("pair", ChatType::Single, &[bob]);
+        let chat = state.create_chat(&input, alice, ws_id).await?;
+
+        state.send_typing(chat.id, alice, ws_id).await?;
+        let ret = state.send_typing(chat.id, alice, ws_id).await;
+        assert!(matches!(ret, Err(AppError::TooManyRequests(_))));
+        state.send_typing(chat.id, bob, ws_id).await?;
+
+        let ret = state.send_typing(chat.id, eve, ws_id).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,222 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use chat_core::{User, Workspace};
+
+use super::session::{generate_token, hash_token};
+use crate::{AppError, AppState};
+
+const INVITE_DURATION: i64 = 60 * 60 * 24 * 7;
+
+/// An invite to sign up to a workspace, the token is only shown once
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WorkspaceInvite {
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl AppState {
+    /// Invite someone to the workspace of the user, any member can invite
+    pub async fn create_workspace_invite(&self, user: &User) -> Result<WorkspaceInvite, AppError> {
+        let token = generate_token();
+        let expires_at = Utc::now() + Duration::seconds(INVITE_DURATION);
+        sqlx::query(
+            r#"
+                INSERT INTO workspace_invites (ws_id,token_hash,created_by,expires_at)
+                VALUES ($1,$2,$3,$4)
+            "#,
+        )
+        .bind(user.ws_id)
+        .bind(hash_token(&token))
+        .bind(user.id)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(WorkspaceInvite { token, expires_at })
+    }
+
+    /// List the users of a workspace, so that clients can pick chat members
+    pub async fn fetch_workspace_users(&self, ws_id: i64) -> Result<Vec<User>, AppError> {
+        let users = sqlx::query_as(
+            r#"
+                SELECT id,ws_id,fullname,email,created_at
+                FROM users
+                WHERE ws_id = $1
+                ORDER BY id
+            "#,
+        )
+        .bind(ws_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(users)
+    }
+}
+
+/// Create the workspace if the name is free, true if it was created. A signup
+/// racing for the same name waits for the other one and finds its workspace
+pub(super) async fn find_or_create_workspace(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    name: &str,
+) -> Result<(Workspace, bool), AppError> {
+    let ws: Option<Workspace> = sqlx::query_as(
+        r#"
+            INSERT INTO workspaces (name) VALUES ($1)
+            ON CONFLICT (name) DO NOTHING
+            RETURNING id,name,owner_id,created_at
+        "#,
+    )
+    .bind(name)
+    .fetch_optional(&mut **tx)
+    .await?;
+    if let Some(ws) = ws {
+        return Ok((ws, true));
+    }
+
+    let ws =
+        sqlx::query_as(r#"SELECT id,name,owner_id,created_at FROM workspaces WHERE name = $1"#)
+            .bind(name)
+            .fetch_one(&mut **tx)
+            .await?;
+    Ok((ws, false))
+}
+
+pub(super) async fn set_workspace_owner(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ws_id: i64,
+    owner_id: i64,
+) -> Result<(), AppError> {
+    sqlx::query(r#"UPDATE workspaces SET owner_id = $2 WHERE id = $1"#)
+        .bind(ws_id)
+        .bind(owner_id)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+/// Use up an invite to the workspace for the user who just signed up with it
+pub(super) async fn use_workspace_invite(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ws: &Workspace,
+    token: &str,
+    user_id: i64,
+) -> Result<(), AppError> {
+    let ret = sqlx::query(
+        r#"
+            UPDATE workspace_invites SET used_by = $3
+            WHERE token_hash = $1 AND ws_id = $2
+            AND used_by IS NULL AND expires_at > NOW()
+        "#,
+    )
+    .bind(hash_token(token))
+    .bind(ws.id)
+    .bind(user_id)
+    .execute(&mut **tx)
+    .await?;
+    if ret.rows_affected() == 0 {
+        return Err(AppError::PermissionDenied(format!(
+            "invalid or used invite for workspace {}",
+            ws.name
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+impl AppState {
+    async fn find_workspace_by_name(&self, name: &str) -> Result<Option<Workspace>, AppError> {
+        let ws =
+            sqlx::query_as(r#"SELECT id,name,owner_id,created_at FROM workspaces WHERE name = $1"#)
+                .bind(name)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(ws)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::CreateUser, AppConfig};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn signup_should_create_or_join_workspace() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+
+        let alice = state
+            .create_user(&CreateUser::new(
+                "acme",
+                "Alice",
+                "alice@acme.org",
+                "alice1988",
+            ))
+            .await?;
+        let ws = state
+            .find_workspace_by_name("acme")
+            .await?
+            .expect("workspace should be created");
+        assert_eq!(ws.id, alice.ws_id);
+        assert_eq!(ws.owner_id, Some(alice.id));
+
+        // joining takes an invite, and nothing is left behind without one
+        let mut input = CreateUser::new("acme", "Bob", "bob@acme.org", "bob1988");
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        assert!(state.find_user_by_email("bob@acme.org").await?.is_none());
+
+        let invite = state.create_workspace_invite(&alice).await?;
+        input.invite = Some(invite.token.clone());
+        let bob = state.create_user(&input).await?;
+        assert_eq!(bob.ws_id, ws.id);
+        let ws = state.find_workspace_by_name("acme").await?.unwrap();
+        assert_eq!(ws.owner_id, Some(alice.id));
+
+        // an invite is used once, and only for its own workspace
+        let mut input = CreateUser::new("acme", "Eve", "eve@acme.org", "eve1988");
+        input.invite = Some(invite.token);
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let peter = state
+            .create_user(&CreateUser::new(
+                "initech",
+                "Peter",
+                "peter@initech.com",
+                "tps1999",
+            ))
+            .await?;
+        input.invite = Some(state.create_workspace_invite(&peter).await?.token);
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let users = state.fetch_workspace_users(ws.id).await?;
+        assert_eq!(
+            users.iter().map(|u| u.id).collect::<Vec<_>>(),
+            vec![alice.id, bob.id]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn racing_signups_should_not_share_a_new_workspace() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test(AppConfig::load()?).await?;
+
+        let first = CreateUser::new("globex", "Hank", "hank@globex.org", "hunter42");
+        let second = CreateUser::new("globex", "Mallory", "mallory@globex.org", "hunter42");
+        let (first, second) = tokio::join!(state.create_user(&first), state.create_user(&second));
+        let (owner, other) = match (first, second) {
+            (Ok(owner), other) | (other, Ok(owner)) => (owner, other),
+            (Err(a), Err(b)) => panic!("both signups failed: {:?}, {:?}", a, b),
+        };
+        assert!(matches!(other, Err(AppError::PermissionDenied(_))));
+        let ws = state.find_workspace_by_name("globex").await?.unwrap();
+        assert_eq!(ws.owner_id, Some(owner.id));
+
+        Ok(())
+    }
+}
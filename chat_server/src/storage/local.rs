@@ -0,0 +1,90 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use super::Storage;
+use crate::AppError;
+
+/// Files in a directory of the local disk, served by chat_server itself
+pub struct LocalStorage {
+    base_dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(base_dir: impl AsRef<Path>) -> Self {
+        Self {
+            base_dir: base_dir.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, data: Vec<u8>, _content_type: &str) -> Result<(), AppError> {
+        let path = self.base_dir.join(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // write aside and rename, a concurrent upload of the same file must not
+        // see a partial one
+        let tmp = path.with_file_name(format!(
+            ".{}.{}",
+            uuid::Uuid::now_v7(),
+            path.file_name().unwrap_or_default().to_string_lossy()
+        ));
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, AppError> {
+        match tokio::fs::read(self.base_dir.join(key)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, AppError> {
+        Ok(tokio::fs::try_exists(self.base_dir.join(key)).await?)
+    }
+
+    async fn presigned_url(
+        &self,
+        _key: &str,
+        _expires: Duration,
+    ) -> Result<Option<String>, AppError> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn local_storage_should_work() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = LocalStorage::new(dir.path());
+
+        assert!(!storage.exists("1/abc/def/0123.png").await?);
+        assert!(storage.get("1/abc/def/0123.png").await?.is_none());
+        storage
+            .put("1/abc/def/0123.png", b"meow".to_vec(), "image/png")
+            .await?;
+        assert!(storage.exists("1/abc/def/0123.png").await?);
+        assert_eq!(
+            storage.get("1/abc/def/0123.png").await?,
+            Some(b"meow".to_vec())
+        );
+        assert!(storage
+            .presigned_url("1/abc/def/0123.png", Duration::from_secs(60))
+            .await?
+            .is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+mod local;
+mod s3;
+
+use std::{future::Future, time::Duration};
+
+pub use local::LocalStorage;
+pub use s3::S3Storage;
+
+use crate::{config::StorageConfig, AppError};
+
+/// Where uploaded files live, keyed by their content addressed path
+pub trait Storage {
+    fn put(
+        &self,
+        key: &str,
+        data: Vec<u8>,
+        content_type: &str,
+    ) -> impl Future<Output = Result<(), AppError>> + Send;
+
+    /// The content of a file, `None` if there is no such file
+    fn get(&self, key: &str) -> impl Future<Output = Result<Option<Vec<u8>>, AppError>> + Send;
+
+    fn exists(&self, key: &str) -> impl Future<Output = Result<bool, AppError>> + Send;
+
+    /// A url clients can download the file from directly for a limited time,
+    /// `None` if the backend can't hand out such urls and the file is served by us
+    fn presigned_url(
+        &self,
+        key: &str,
+        expires: Duration,
+    ) -> impl Future<Output = Result<Option<String>, AppError>> + Send;
+}
+
+/// The backend picked in the config
+pub enum FileStorage {
+    Local(LocalStorage),
+    S3(S3Storage),
+}
+
+impl FileStorage {
+    pub fn new(config: &StorageConfig) -> Result<Self, AppError> {
+        match config {
+            StorageConfig::Local { base_dir } => Ok(Self::Local(LocalStorage::new(base_dir))),
+            StorageConfig::S3(config) => Ok(Self::S3(S3Storage::try_new(config)?)),
+        }
+    }
+}
+
+impl Storage for FileStorage {
+    async fn put(&self, key: &str, data: Vec<u8>, content_type: &str) -> Result<(), AppError> {
+        match self {
+            Self::Local(s) => s.put(key, data, content_type).await,
+            Self::S3(s) => s.put(key, data, content_type).await,
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, AppError> {
+        match self {
+            Self::Local(s) => s.get(key).await,
+            Self::S3(s) => s.get(key).await,
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, AppError> {
+        match self {
+            Self::Local(s) => s.exists(key).await,
+            Self::S3(s) => s.exists(key).await,
+        }
+    }
+
+    async fn presigned_url(
+        &self,
+        key: &str,
+        expires: Duration,
+    ) -> Result<Option<String>, AppError> {
+        match self {
+            Self::Local(s) => s.presigned_url(key, expires).await,
+            Self::S3(s) => s.presigned_url(key, expires).await,
+        }
+    }
+}
@@ -1,2 +0,0 @@
-mod jwt;
-pub use jwt::{DecodingKey, EncodingKey};
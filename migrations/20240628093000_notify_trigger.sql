@@ -0,0 +1,29 @@
+-- notify chat and message changes so that notify_server can push them to clients
+
+-- chat created / updated / deleted, old and new rows are both sent so the
+-- listener can notify members that were removed as well
+CREATE OR REPLACE FUNCTION chat_updated() RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_notify('chat_updated', json_build_object('op', TG_OP, 'old', OLD, 'new', NEW)::text);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER chat_updated_trigger
+    AFTER INSERT OR UPDATE OR DELETE ON chats
+    FOR EACH ROW EXECUTE PROCEDURE chat_updated();
+
+-- message created, only ids are sent since pg_notify payload is limited to 8000 bytes
+CREATE OR REPLACE FUNCTION chat_message_created() RETURNS TRIGGER AS $$
+DECLARE
+    USERS BIGINT[];
+BEGIN
+    SELECT members INTO USERS FROM chats WHERE id = NEW.chat_id;
+    PERFORM pg_notify('chat_message_created', json_build_object('id', NEW.id, 'members', USERS)::text);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER chat_message_created_trigger
+    AFTER INSERT ON messages
+    FOR EACH ROW EXECUTE PROCEDURE chat_message_created();
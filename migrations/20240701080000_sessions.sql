@@ -0,0 +1,23 @@
+-- sessions created on signin / signup, access tokens carry the session id so
+-- revoking the session invalidates them before they expire
+CREATE TABLE IF NOT EXISTS sessions(
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS sessions_user_id_index ON sessions(user_id);
+
+-- refresh tokens are opaque, only their sha256 is stored. a token is used once,
+-- refreshing marks it used and issues a new one for the same session
+CREATE TABLE IF NOT EXISTS refresh_tokens(
+    id BIGSERIAL PRIMARY KEY,
+    session_id BIGINT NOT NULL REFERENCES sessions(id) ON DELETE CASCADE,
+    token_hash CHAR(64) NOT NULL UNIQUE,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS refresh_tokens_session_id_index ON refresh_tokens(session_id);
@@ -0,0 +1,28 @@
+-- workspaces separate the teams hosted on one deployment, users and chats
+-- belong to exactly one workspace
+
+CREATE TABLE IF NOT EXISTS workspaces(
+    id BIGSERIAL PRIMARY KEY,
+    name VARCHAR(32) NOT NULL UNIQUE,
+    -- the user who created it, 0 until the creating user row exists
+    owner_id BIGINT NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+-- existing users and chats are moved to a default workspace
+INSERT INTO workspaces(name, owner_id)
+    SELECT 'default', COALESCE(MIN(id), 0) FROM users;
+
+ALTER TABLE users ADD COLUMN ws_id BIGINT REFERENCES workspaces(id);
+UPDATE users SET ws_id = (SELECT id FROM workspaces WHERE name = 'default');
+ALTER TABLE users ALTER COLUMN ws_id SET NOT NULL;
+
+ALTER TABLE chats ADD COLUMN ws_id BIGINT REFERENCES workspaces(id);
+UPDATE chats SET ws_id = (SELECT id FROM workspaces WHERE name = 'default');
+ALTER TABLE chats ALTER COLUMN ws_id SET NOT NULL;
+
+-- chat names are unique per workspace instead of globally
+ALTER TABLE chats DROP CONSTRAINT IF EXISTS chats_name_key;
+ALTER TABLE chats ADD CONSTRAINT chats_ws_id_name_key UNIQUE (ws_id, name);
+
+CREATE INDEX IF NOT EXISTS users_ws_id_index ON users(ws_id);
@@ -0,0 +1,47 @@
+-- move chat membership from the chats.members array to a join table, so that
+-- members have foreign keys, roles and per member state
+
+CREATE TYPE chat_member_role AS ENUM('owner','admin','member');
+
+CREATE TABLE IF NOT EXISTS chat_members(
+    chat_id BIGINT NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    role chat_member_role NOT NULL DEFAULT 'member',
+    joined_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_read_message_id BIGINT REFERENCES messages(id) ON DELETE SET NULL,
+    PRIMARY KEY (chat_id, user_id)
+);
+
+-- "chats of user X", the primary key already covers "members of chat X"
+CREATE INDEX IF NOT EXISTS chat_members_user_id_index ON chat_members(user_id);
+
+-- backfill, ids in the array were never checked so skip the ones without a user
+INSERT INTO chat_members (chat_id, user_id, joined_at)
+    SELECT c.id, u.id, c.created_at
+    FROM chats c
+    CROSS JOIN UNNEST(c.members) AS m(user_id)
+    JOIN users u ON u.id = m.user_id
+    ON CONFLICT DO NOTHING;
+
+-- the creator was not recorded, the lowest user id is the best guess
+UPDATE chat_members cm SET role = 'owner'
+    FROM (SELECT chat_id, MIN(user_id) AS user_id FROM chat_members GROUP BY chat_id) o
+    WHERE cm.chat_id = o.chat_id AND cm.user_id = o.user_id;
+
+-- a row trigger on chats can't see the members, they are inserted after the chat
+-- and already gone when it is deleted. chat_server sends chat_updated itself, in the
+-- same transaction as the change
+DROP TRIGGER IF EXISTS chat_updated_trigger ON chats;
+DROP FUNCTION IF EXISTS chat_updated();
+
+CREATE OR REPLACE FUNCTION chat_message_created() RETURNS TRIGGER AS $$
+DECLARE
+    USERS BIGINT[];
+BEGIN
+    USERS := ARRAY(SELECT user_id FROM chat_members WHERE chat_id = NEW.chat_id);
+    PERFORM pg_notify('chat_message_created', json_build_object('id', NEW.id, 'members', USERS)::text);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+ALTER TABLE chats DROP COLUMN members;
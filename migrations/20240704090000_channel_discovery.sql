@@ -0,0 +1,2 @@
+-- browsing the public channels of a workspace, ordered by name
+CREATE INDEX IF NOT EXISTS chats_ws_id_type_name_index ON chats(ws_id, type, name);
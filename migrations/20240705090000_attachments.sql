@@ -0,0 +1,19 @@
+-- uploaded files, with what the upload pipeline found out about them
+CREATE TABLE IF NOT EXISTS attachments(
+    id BIGSERIAL PRIMARY KEY,
+    ws_id BIGINT NOT NULL REFERENCES workspaces(id),
+    -- the url messages refer to the file by, it includes the workspace
+    url TEXT NOT NULL UNIQUE,
+    -- only for images larger than a thumbnail
+    thumbnail_url TEXT,
+    mime VARCHAR(128) NOT NULL,
+    size BIGINT NOT NULL,
+    width INT,
+    height INT,
+    -- who uploaded the file first
+    uploader_id BIGINT NOT NULL REFERENCES users(id),
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+-- a thumbnail is readable wherever its image is
+CREATE INDEX IF NOT EXISTS attachments_thumbnail_url_index ON attachments(thumbnail_url);
@@ -0,0 +1,16 @@
+-- messages can be edited and deleted by their sender, deleted messages keep
+-- their place in the chat with no content
+ALTER TABLE messages ADD COLUMN updated_at TIMESTAMPTZ;
+ALTER TABLE messages ADD COLUMN deleted_at TIMESTAMPTZ;
+
+-- the previous versions of edited messages, newest last
+CREATE TABLE IF NOT EXISTS message_edits(
+    id BIGSERIAL PRIMARY KEY,
+    message_id BIGINT NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    content TEXT NOT NULL,
+    images TEXT[] NOT NULL,
+    -- when this version was replaced
+    edited_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS message_edits_message_id_index ON message_edits(message_id, id);
@@ -0,0 +1,30 @@
+-- replies to a message form its thread, threads are one level deep
+ALTER TABLE messages ADD COLUMN parent_id BIGINT REFERENCES messages(id);
+
+CREATE INDEX IF NOT EXISTS parent_id_created_at_index ON messages(parent_id, created_at, id)
+    WHERE parent_id IS NOT NULL;
+
+-- who hears about a message: the chat members, or for a reply the members who
+-- took part in its thread, i.e. sent the parent or a reply
+CREATE OR REPLACE FUNCTION message_recipients(chat BIGINT, parent BIGINT) RETURNS BIGINT[] AS $$
+    SELECT ARRAY(
+        SELECT cm.user_id FROM chat_members cm
+        WHERE cm.chat_id = chat AND (
+            parent IS NULL OR cm.user_id IN (
+                SELECT sender_id FROM messages WHERE id = parent
+                UNION
+                SELECT sender_id FROM messages WHERE parent_id = parent
+            )
+        )
+    )
+$$ LANGUAGE sql STABLE;
+
+CREATE OR REPLACE FUNCTION chat_message_created() RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_notify('chat_message_created', json_build_object(
+        'id', NEW.id,
+        'members', message_recipients(NEW.chat_id, NEW.parent_id)
+    )::text);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,8 @@
+-- emoji reactions, a user reacts to a message with an emoji at most once
+CREATE TABLE IF NOT EXISTS message_reactions(
+    message_id BIGINT NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    emoji VARCHAR(64) NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (message_id, user_id, emoji)
+);
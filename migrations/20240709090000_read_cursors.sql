@@ -0,0 +1,4 @@
+-- unread counts walk the messages of a chat after the read cursor,
+-- chat_members.last_read_message_id, by id
+CREATE INDEX IF NOT EXISTS chat_id_id_index ON messages(chat_id, id)
+    WHERE parent_id IS NULL AND deleted_at IS NULL;
@@ -0,0 +1,9 @@
+-- pg_notify payloads are limited to 8000 bytes, so the recipients of a message, up to
+-- all members of a large channel, are no longer sent. notify_server finds them with
+-- message_recipients once it loaded the message
+CREATE OR REPLACE FUNCTION chat_message_created() RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_notify('chat_message_created', json_build_object('id', NEW.id)::text);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,13 @@
+-- everyone who uploaded a file, the same content is stored once but may be
+-- uploaded by several users. Messages can only refer to files uploaded by
+-- their sender or by a member of their chat
+CREATE TABLE IF NOT EXISTS attachment_uploaders(
+    url TEXT NOT NULL REFERENCES attachments(url) ON DELETE CASCADE,
+    uploader_id BIGINT NOT NULL REFERENCES users(id),
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (url, uploader_id)
+);
+
+INSERT INTO attachment_uploaders (url, uploader_id, created_at)
+    SELECT url, uploader_id, created_at FROM attachments
+    ON CONFLICT DO NOTHING;
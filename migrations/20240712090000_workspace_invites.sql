@@ -0,0 +1,21 @@
+-- the owner of a workspace is a real user. it is NULL only until the user who
+-- creates the workspace is inserted, in the same transaction
+ALTER TABLE workspaces ALTER COLUMN owner_id DROP NOT NULL;
+UPDATE workspaces SET owner_id = NULL WHERE owner_id = 0;
+ALTER TABLE workspaces ADD CONSTRAINT workspaces_owner_id_fkey
+    FOREIGN KEY (owner_id) REFERENCES users(id);
+
+-- two signups with the same email can't both get in
+CREATE UNIQUE INDEX IF NOT EXISTS users_email_key ON users(email);
+
+-- signing up to an existing workspace takes an invite from one of its members.
+-- invites are opaque, only their sha256 is stored, and each is used once
+CREATE TABLE IF NOT EXISTS workspace_invites(
+    id BIGSERIAL PRIMARY KEY,
+    ws_id BIGINT NOT NULL REFERENCES workspaces(id),
+    token_hash CHAR(64) NOT NULL UNIQUE,
+    created_by BIGINT NOT NULL REFERENCES users(id),
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_by BIGINT REFERENCES users(id),
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,112 @@
+use anyhow::Result;
+use chat_core::{load_config, read_pem, PublicKeyConfig};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AppConfig {
+    pub server: ServerConfig,
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub sse: SseConfig,
+    #[serde(default)]
+    pub presence: PresenceConfig,
+    #[serde(default)]
+    pub ws: WsConfig,
+}
+
+impl AppConfig {
+    pub fn load() -> Result<Self> {
+        // Merge --config > env NOTIFY_CONFIG > ./notify.yaml > /etc/config/notify.yaml,
+        // every field can be overridden by env, e.g. NOTIFY_SERVER__PORT
+        let mut config: Self = load_config("notify.yaml", "NOTIFY_", &[])?;
+        // keys are either inline PEM or a path to the PEM file
+        for key in config.auth.keys.iter_mut() {
+            key.pk = read_pem(&key.pk)?;
+        }
+        if config.auth.jwks_url.is_none() && config.auth.keys.is_empty() {
+            anyhow::bail!("either auth.jwks_url or auth.keys must be set");
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ServerConfig {
+    #[serde(default = "default_host")]
+    pub host: String,
+    pub port: u16,
+    pub db_url: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AuthConfig {
+    /// chat_server's key set, fetched at startup and again when a token has an unknown kid
+    pub jwks_url: Option<String>,
+    /// static verification keys, for when chat_server can't be reached
+    #[serde(default)]
+    pub keys: Vec<PublicKeyConfig>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SseConfig {
+    /// keep-alive interval in seconds
+    pub keep_alive: u64,
+    /// max concurrent event streams of a single user
+    pub max_connections_per_user: usize,
+    /// events kept per user for streams resuming with `Last-Event-ID`
+    pub replay_capacity: usize,
+    /// seconds the events of a user without a stream are kept after the last one
+    pub replay_retention: u64,
+}
+
+impl Default for SseConfig {
+    fn default() -> Self {
+        Self {
+            keep_alive: 15,
+            max_connections_per_user: 8,
+            replay_capacity: 256,
+            replay_retention: 300,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PresenceConfig {
+    /// seconds a user stays online after their last stream dropped, to ride out reconnects
+    pub grace_period: u64,
+    /// seconds after which a user with no stream is offline, away until then
+    pub offline_after: u64,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        Self {
+            grace_period: 10,
+            offline_after: 300,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WsConfig {
+    /// seconds between the pings sent to the client
+    pub ping_interval: u64,
+    /// seconds without any frame from the client, pongs included, after which it's dead
+    pub timeout: u64,
+}
+
+impl Default for WsConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: 15,
+            timeout: 45,
+        }
+    }
+}
+
+fn default_host() -> String {
+    "0.0.0.0".to_string()
+}
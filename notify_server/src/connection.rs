@@ -0,0 +1,134 @@
+use std::{collections::HashSet, sync::Arc};
+
+use chrono::Utc;
+use futures::Stream;
+use tokio::sync::broadcast;
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    StreamExt,
+};
+use tracing::{info, warn};
+
+use crate::{presence::publish_presence, replay::StreamEvent, AppError, AppEvent, AppState};
+
+const CHANNEL_CAPACITY: usize = 256;
+
+/// An event for a client with its id, no id for a `Resync` after lagging behind,
+/// so that a reconnect can still replay what was dropped
+pub(crate) type Outgoing = (Option<i64>, Arc<AppEvent>);
+
+/// Subscribe to the events of the user, the ones after `last_event_id` are
+/// replayed first. Shared by all transports, the user is connected for
+/// presence until the stream is dropped
+pub(crate) fn subscribe(
+    state: &AppState,
+    user_id: i64,
+    last_event_id: Option<i64>,
+) -> Result<impl Stream<Item = Outgoing>, AppError> {
+    let rx = {
+        let tx = state
+            .users
+            .entry(user_id)
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0);
+        if tx.receiver_count() >= state.config.sse.max_connections_per_user {
+            let msg = format!("User {} has too many connections", user_id);
+            warn!(msg);
+            return Err(AppError::TooManyRequests(msg));
+        }
+        tx.subscribe()
+    };
+    info!("User {} connected", user_id);
+    if state.presence.connect(user_id, Utc::now()) {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let presence = state.presence.get(user_id, Utc::now());
+            publish_presence(&state, presence).await;
+        });
+    }
+
+    // dropped together with the stream when the client goes away
+    let connection = Connection {
+        state: state.clone(),
+        user_id,
+    };
+    // subscribed before the replay is read, so nothing falls in between
+    let replay = match last_event_id {
+        Some(last_id) => state.replay.since(user_id, last_id).unwrap_or_else(|| {
+            info!("User {} can't resume from {}", user_id, last_id);
+            vec![StreamEvent {
+                id: state.replay.last_id(),
+                event: Arc::new(AppEvent::Resync),
+            }]
+        }),
+        None => Vec::new(),
+    };
+    // ids are handed out before the events are sent, so live events can come
+    // out of order, only the ones that were replayed are dropped
+    let replayed: HashSet<i64> = replay.iter().map(|e| e.id).collect();
+    let replay = tokio_stream::iter(replay).map(|e| (Some(e.id), e.event));
+    let live = BroadcastStream::new(rx).filter_map(move |v| {
+        let _connection = &connection;
+        live_event(&replayed, user_id, v)
+    });
+
+    Ok(replay.chain(live))
+}
+
+fn live_event(
+    replayed: &HashSet<i64>,
+    user_id: i64,
+    v: Result<StreamEvent, BroadcastStreamRecvError>,
+) -> Option<Outgoing> {
+    match v {
+        Ok(e) if replayed.contains(&e.id) => None,
+        Ok(e) => Some((Some(e.id), e.event)),
+        Err(e) => {
+            warn!("User {} lagged behind: {:?}", user_id, e);
+            Some((None, Arc::new(AppEvent::Resync)))
+        }
+    }
+}
+
+/// An open event stream, counted for the presence of its user
+struct Connection {
+    state: AppState,
+    user_id: i64,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        info!("User {} disconnected", self.user_id);
+        self.state.presence.disconnect(self.user_id, Utc::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_core::Typing;
+
+    fn event(id: i64) -> Result<StreamEvent, BroadcastStreamRecvError> {
+        Ok(StreamEvent {
+            id,
+            event: Arc::new(AppEvent::Typing(Typing {
+                chat_id: 1,
+                user_id: 2,
+            })),
+        })
+    }
+
+    #[test]
+    fn live_events_should_only_skip_replayed_ones() {
+        let replayed = HashSet::from([10, 12]);
+        let ids: Vec<_> = [event(12), event(13), event(11), event(10)]
+            .into_iter()
+            .filter_map(|v| live_event(&replayed, 1, v))
+            .map(|(id, _)| id)
+            .collect();
+        // 11 was sent after 13, it still goes out
+        assert_eq!(ids, vec![Some(13), Some(11)]);
+
+        let lagged = live_event(&replayed, 1, Err(BroadcastStreamRecvError::Lagged(3)));
+        assert!(matches!(lagged, Some((None, e)) if matches!(*e, AppEvent::Resync)));
+    }
+}
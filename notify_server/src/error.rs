@@ -0,0 +1,37 @@
+use axum::http::StatusCode;
+use axum::response::Json;
+use axum::response::{IntoResponse, Response};
+use chat_core::CoreError;
+use serde_json::json;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AppError {
+    #[error("sqlx error: {0}")]
+    SqlxError(#[from] sqlx::Error),
+    #[error("{0}")]
+    CoreError(#[from] CoreError),
+    #[error("invalid input:{0}")]
+    InvalidInput(String),
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("not found: {0}")]
+    NotFound(String),
+    #[error("too many requests: {0}")]
+    TooManyRequests(String),
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response<axum::body::Body> {
+        let status = match self {
+            AppError::SqlxError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::CoreError(ref e) => e.status_code(),
+            AppError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            AppError::PermissionDenied(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+        };
+
+        (status, Json(json!({"error":self.to_string()}))).into_response()
+    }
+}
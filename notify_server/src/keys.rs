@@ -0,0 +1,157 @@
+use std::{
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use chat_core::{token_kid, CoreError, DecodingKey, Jwks, UserClaims};
+use tracing::{info, warn};
+
+use crate::config::AuthConfig;
+
+/// Unknown kids trigger a fetch, so limit how often a bad token can make us fetch
+const MIN_FETCH_INTERVAL: Duration = Duration::from_secs(30);
+/// tokens wait on a fetch, so a hung chat_server must not hold them for long
+const FETCH_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Verification keys, the static ones from the config plus the ones fetched from jwks_url
+pub struct KeyStore {
+    jwks_url: Option<String>,
+    static_keys: DecodingKey,
+    keys: RwLock<DecodingKey>,
+    last_fetch: Mutex<Option<Instant>>,
+    client: reqwest::Client,
+}
+
+impl KeyStore {
+    pub async fn try_new(config: &AuthConfig) -> Result<Self> {
+        let static_keys = DecodingKey::load(&config.keys)?;
+        let store = Self {
+            jwks_url: config.jwks_url.clone(),
+            keys: RwLock::new(static_keys.clone()),
+            static_keys,
+            last_fetch: Mutex::new(None),
+            client: reqwest::Client::builder()
+                .connect_timeout(FETCH_CONNECT_TIMEOUT)
+                .timeout(FETCH_TIMEOUT)
+                .build()?,
+        };
+        // chat_server may not be up yet, the keys are fetched again on the first token
+        if let Err(e) = store.fetch().await {
+            warn!("Failed to fetch jwks: {:?}", e);
+        }
+        Ok(store)
+    }
+
+    pub async fn verify(&self, token: &str) -> Result<UserClaims, CoreError> {
+        let kid = token_kid(token)?;
+        if !self.read().contains(&kid) {
+            // the signing key may have been rotated since the last fetch
+            if let Err(e) = self.fetch().await {
+                warn!("Failed to fetch jwks: {:?}", e);
+            }
+        }
+        self.read().verify(token)
+    }
+
+    async fn fetch(&self) -> Result<()> {
+        let Some(url) = &self.jwks_url else {
+            return Ok(());
+        };
+        // only the slot is taken under the lock, others verify with the keys at hand
+        // rather than waiting on the request
+        {
+            let mut last_fetch = self.last_fetch.lock().expect("last fetch lock poisoned");
+            if last_fetch.is_some_and(|t| t.elapsed() < MIN_FETCH_INTERVAL) {
+                return Ok(());
+            }
+            *last_fetch = Some(Instant::now());
+        }
+
+        let jwks: Jwks = self
+            .client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let mut keys = DecodingKey::from_jwks(&jwks)?;
+        keys.extend(self.static_keys.clone());
+        *self.keys.write().expect("keys lock poisoned") = keys;
+        info!("Fetched {} keys from {}", jwks.keys.len(), url);
+
+        Ok(())
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, DecodingKey> {
+        self.keys.read().expect("keys lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::get, Json, Router};
+    use chat_core::EncodingKey;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn key_store_should_fetch_jwks() -> Result<()> {
+        let ek = EncodingKey::load("dev", include_str!("../../chat_core/fixtures/encoding.pem"))?;
+        let jwks = ek.decoding_key().jwks();
+        let app = Router::new().route("/jwks.json", get(move || async move { Json(jwks) }));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config = AuthConfig {
+            jwks_url: Some(format!("http://{}/jwks.json", addr)),
+            keys: vec![],
+        };
+        let store = KeyStore::try_new(&config).await?;
+
+        let token = ek.sign(UserClaims {
+            uid: 1,
+            wid: 1,
+            sid: 7,
+        })?;
+        let claims = store.verify(&token).await?;
+        assert_eq!(claims.sid, 7);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hung_jwks_should_not_block_verification() -> Result<()> {
+        // accepts connections but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let mut conns = Vec::new();
+            while let Ok((conn, _)) = listener.accept().await {
+                conns.push(conn);
+            }
+        });
+
+        let config = AuthConfig {
+            jwks_url: Some(format!("http://{}/jwks.json", addr)),
+            keys: vec![],
+        };
+        let wait = FETCH_TIMEOUT + Duration::from_secs(2);
+        let store = tokio::time::timeout(wait, KeyStore::try_new(&config)).await??;
+
+        let ek = EncodingKey::load("dev", include_str!("../../chat_core/fixtures/encoding.pem"))?;
+        let token = ek.sign(UserClaims {
+            uid: 1,
+            wid: 1,
+            sid: 7,
+        })?;
+        // the fetch of try_new counts, so this one doesn't wait on the endpoint again
+        let ret = tokio::time::timeout(Duration::from_secs(1), store.verify(&token)).await?;
+        assert!(ret.is_err());
+
+        Ok(())
+    }
+}
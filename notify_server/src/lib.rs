@@ -1,14 +1,121 @@
-use axum::{response::IntoResponse, routing::get, Router};
+mod config;
+mod connection;
+mod error;
+mod keys;
+mod notify;
+mod presence;
+mod replay;
+mod sse;
+mod ws;
+
+use std::{ops::Deref, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use axum::{middleware::from_fn_with_state, response::IntoResponse, routing::get, Router};
 use axum_extra::response::Html;
+use chat_core::{verify_token, CoreError, RateLimiter, Session, TokenVerify, User, UserCache};
+use dashmap::DashMap;
+use keys::KeyStore;
+use presence::{presence_handler, setup_presence_ticker, PresenceTracker};
+use replay::{setup_replay_pruner, ReplayBuffer, StreamEvent};
 use sse::sse_handler;
-mod sse;
+use tokio::sync::broadcast;
+use ws::ws_handler;
+
+pub use config::AppConfig;
+pub use error::AppError;
+pub use notify::{setup_pg_listener, AppEvent};
 
 const INDEX_HTML: &str = include_str!("../index.html");
+/// typing frames let through per user, the same as chat_server
+const TYPING_INTERVAL: Duration = Duration::from_secs(1);
 
-pub fn get_router() -> Router {
-    Router::new()
-        .route("/", get(index_handler))
+/// Per user broadcast channel, shared by all the connections of that user
+pub type UserMap = DashMap<i64, broadcast::Sender<StreamEvent>>;
+
+#[derive(Clone)]
+pub struct AppState(Arc<AppStateInner>);
+
+pub struct AppStateInner {
+    pub config: AppConfig,
+    pub users: UserMap,
+    pub keys: KeyStore,
+    pub pool: sqlx::PgPool,
+    pub user_cache: UserCache,
+    pub presence: PresenceTracker,
+    pub replay: ReplayBuffer,
+    pub typing_limiter: RateLimiter,
+}
+
+impl AppState {
+    pub async fn try_new(config: AppConfig) -> Result<Self> {
+        let keys = KeyStore::try_new(&config.auth)
+            .await
+            .context("load keys failed")?;
+        let pool = sqlx::PgPool::connect(&config.server.db_url)
+            .await
+            .context("load db error")?;
+        let presence = PresenceTracker::new(&config.presence);
+        let replay = ReplayBuffer::new(&config.sse);
+        Ok(Self(Arc::new(AppStateInner {
+            config,
+            users: DashMap::new(),
+            keys,
+            pool,
+            user_cache: UserCache::default(),
+            presence,
+            replay,
+            typing_limiter: RateLimiter::new(TYPING_INTERVAL),
+        })))
+    }
+}
+
+impl TokenVerify for AppState {
+    type Error = AppError;
+
+    async fn verify(&self, token: &str) -> Result<User, Self::Error> {
+        let claims = self.keys.verify(token).await?;
+        // a failing lookup is a 500, the token may well be fine
+        if !Session::is_active(&self.pool, claims.sid).await? {
+            return Err(CoreError::InvalidToken(format!("session {} revoked", claims.sid)).into());
+        }
+        let user = self
+            .user_cache
+            .get(&self.pool, claims.uid)
+            .await?
+            .ok_or_else(|| CoreError::InvalidToken(format!("user {} not found", claims.uid)))?;
+        if user.ws_id != claims.wid {
+            return Err(
+                CoreError::InvalidToken(format!("workspace {} mismatch", claims.wid)).into(),
+            );
+        }
+        Ok(user)
+    }
+}
+
+impl Deref for AppState {
+    type Target = AppStateInner;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+pub async fn get_router(config: AppConfig) -> Result<Router> {
+    let state = AppState::try_new(config).await?;
+    setup_pg_listener(state.clone()).await?;
+    setup_presence_ticker(state.clone());
+    setup_replay_pruner(state.clone());
+
+    let app = Router::new()
         .route("/events", get(sse_handler))
+        .route("/ws", get(ws_handler))
+        .route("/api/users/presence", get(presence_handler))
+        .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
+        .route("/", get(index_handler))
+        .with_state(state);
+
+    Ok(app)
 }
 
 async fn index_handler() -> impl IntoResponse {
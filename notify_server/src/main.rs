@@ -1,6 +1,6 @@
 use anyhow::Result;
 
-use notify_server::get_router;
+use notify_server::{get_router, AppConfig};
 use tokio::net::TcpListener;
 use tracing::{info, level_filters::LevelFilter};
 use tracing_subscriber::{
@@ -20,9 +20,10 @@ async fn main() -> Result<()> {
 
     tracing_subscriber::registry().with(console_layer).init();
 
-    let addr = format!("0.0.0.0:{}", 6687);
+    let config = AppConfig::load()?;
+    let addr = format!("{}:{}", config.server.host, config.server.port);
 
-    let app = get_router();
+    let app = get_router(config).await?;
 
     let listener = TcpListener::bind(&addr).await?;
     info!("Listening on: {}", addr);
@@ -0,0 +1,524 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use chat_core::{Chat, ChatMemberRole, ChatRead, Message, Typing, CHAT_READ, CHAT_TYPING};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use tracing::{info, warn};
+
+use crate::{presence::UserPresence, AppState};
+
+const CHAT_UPDATED: &str = "chat_updated";
+const CHAT_MESSAGE_CREATED: &str = "chat_message_created";
+const CHAT_MEMBER_UPDATED: &str = "chat_member_updated";
+const CHAT_MESSAGE_UPDATED: &str = "chat_message_updated";
+const MESSAGE_REACTION_UPDATED: &str = "message_reaction_updated";
+
+/// Events pushed to the clients, serialized with the variant name as `event`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "event")]
+pub enum AppEvent {
+    NewChat(Chat),
+    UpdateChat(Chat),
+    DeleteChat(Chat),
+    AddMembers(ChatMembersChanged),
+    RemoveMembers(ChatMembersChanged),
+    UpdateMemberRole(ChatMembersChanged),
+    NewMessage(Message),
+    /// a message in a thread, sent to those who took part in it
+    NewReply(Message),
+    UpdateMessage(Message),
+    /// the message is kept with its `deleted_at` set and no content
+    DeleteMessage(Message),
+    AddReaction(ReactionChanged),
+    RemoveReaction(ReactionChanged),
+    /// the user read a chat on one of their devices
+    ReadChat(ChatRead),
+    /// ephemeral, clients show it for a few seconds
+    Typing(Typing),
+    /// a user who shares a chat came online, went away or offline
+    Presence(UserPresence),
+    /// missed events could not be replayed, reload the chats from chat_server
+    Resync,
+}
+
+/// A membership change, `user_ids` were added, removed or given `role` by `by`.
+/// `chat` is the chat after the change
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ChatMembersChanged {
+    pub chat: Chat,
+    pub user_ids: Vec<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<ChatMemberRole>,
+    pub by: i64,
+}
+
+/// `user_id` reacted to a message with `emoji`, or took it back. `count` is
+/// how many users reacted with it after the change
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ReactionChanged {
+    pub chat_id: i64,
+    pub message_id: i64,
+    pub emoji: String,
+    pub user_id: i64,
+    pub count: i64,
+}
+
+impl AppEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AppEvent::NewChat(_) => "NewChat",
+            AppEvent::UpdateChat(_) => "UpdateChat",
+            AppEvent::DeleteChat(_) => "DeleteChat",
+            AppEvent::AddMembers(_) => "AddMembers",
+            AppEvent::RemoveMembers(_) => "RemoveMembers",
+            AppEvent::UpdateMemberRole(_) => "UpdateMemberRole",
+            AppEvent::NewMessage(_) => "NewMessage",
+            AppEvent::NewReply(_) => "NewReply",
+            AppEvent::UpdateMessage(_) => "UpdateMessage",
+            AppEvent::DeleteMessage(_) => "DeleteMessage",
+            AppEvent::AddReaction(_) => "AddReaction",
+            AppEvent::RemoveReaction(_) => "RemoveReaction",
+            AppEvent::ReadChat(_) => "ReadChat",
+            AppEvent::Typing(_) => "Typing",
+            AppEvent::Presence(_) => "Presence",
+            AppEvent::Resync => "Resync",
+        }
+    }
+
+    /// Events that are stale by the time a stream reconnects, never replayed
+    pub fn is_ephemeral(&self) -> bool {
+        matches!(
+            self,
+            AppEvent::Typing(_) | AppEvent::Presence(_) | AppEvent::Resync
+        )
+    }
+}
+
+/// An event together with the users it should be delivered to
+#[derive(Debug)]
+pub(crate) struct Notification {
+    pub(crate) user_ids: HashSet<i64>,
+    pub(crate) event: Arc<AppEvent>,
+}
+
+// payloads carry ids only, pg_notify is limited to 8000 bytes. Chats, messages and
+// recipients are loaded here, except the users that can't be found from the members
+// after the change, which come in chunks
+
+#[derive(Debug, Deserialize)]
+struct ChatUpdated {
+    op: String,
+    id: i64,
+    /// a deleted chat, without its members
+    #[serde(default)]
+    chat: Option<Chat>,
+    /// users that are no longer members, removed ones or all of a deleted chat
+    #[serde(default)]
+    users: Vec<i64>,
+    /// the members are notified with the first chunk of `users` only
+    #[serde(default)]
+    chunk: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatMemberUpdated {
+    op: String,
+    chat_id: i64,
+    user_ids: Vec<i64>,
+    #[serde(default)]
+    role: Option<ChatMemberRole>,
+    by: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatMessageCreated {
+    id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatMessageUpdated {
+    op: String,
+    id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatReadUpdated {
+    user_id: i64,
+    read: ChatRead,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessageReactionUpdated {
+    op: String,
+    parent_id: Option<i64>,
+    #[serde(flatten)]
+    change: ReactionChanged,
+}
+
+/// Listen to the chat triggers and fan out the events to the connected members
+pub async fn setup_pg_listener(state: AppState) -> Result<()> {
+    let mut listener = PgListener::connect_with(&state.pool).await?;
+    listener
+        .listen_all([
+            CHAT_UPDATED,
+            CHAT_MEMBER_UPDATED,
+            CHAT_MESSAGE_CREATED,
+            CHAT_MESSAGE_UPDATED,
+            MESSAGE_REACTION_UPDATED,
+            CHAT_READ,
+            CHAT_TYPING,
+        ])
+        .await?;
+
+    tokio::spawn(async move {
+        loop {
+            let notif = match listener.recv().await {
+                Ok(notif) => notif,
+                Err(e) => {
+                    warn!("Failed to receive notification: {:?}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            info!("Received notification: {:?}", notif);
+
+            match Notification::load(notif.channel(), notif.payload(), &state).await {
+                Ok(Some(notification)) => state.notify(notification),
+                Ok(None) => {}
+                Err(e) => warn!("Failed to load notification: {:?}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+impl Notification {
+    async fn load(channel: &str, payload: &str, state: &AppState) -> Result<Option<Self>> {
+        match channel {
+            CHAT_UPDATED => {
+                let mut payload: ChatUpdated = serde_json::from_str(payload)?;
+                let chat = match payload.chat.take() {
+                    Some(chat) => Some(chat),
+                    None => fetch_chat(payload.id, state).await?,
+                };
+                Ok(chat.and_then(|chat| Self::from_chat_updated(payload, chat)))
+            }
+            CHAT_MEMBER_UPDATED => {
+                let payload: ChatMemberUpdated = serde_json::from_str(payload)?;
+                let chat = fetch_chat(payload.chat_id, state).await?;
+                Ok(chat.and_then(|chat| Self::from_chat_member_updated(payload, chat)))
+            }
+            CHAT_MESSAGE_CREATED => {
+                let payload: ChatMessageCreated = serde_json::from_str(payload)?;
+                let Some(message) = fetch_message(payload.id, state).await? else {
+                    return Ok(None);
+                };
+                let user_ids = fetch_recipients(message.chat_id, message.parent_id, state).await?;
+
+                Ok(Some(Self {
+                    user_ids,
+                    event: Arc::new(match message.parent_id {
+                        Some(_) => AppEvent::NewReply(message),
+                        None => AppEvent::NewMessage(message),
+                    }),
+                }))
+            }
+            CHAT_MESSAGE_UPDATED => {
+                let payload: ChatMessageUpdated = serde_json::from_str(payload)?;
+                let Some(message) = fetch_message(payload.id, state).await? else {
+                    return Ok(None);
+                };
+                let user_ids = fetch_recipients(message.chat_id, message.parent_id, state).await?;
+                let event = match payload.op.as_str() {
+                    "UPDATE" => AppEvent::UpdateMessage(message),
+                    "DELETE" => AppEvent::DeleteMessage(message),
+                    _ => return Ok(None),
+                };
+
+                Ok(Some(Self {
+                    user_ids,
+                    event: Arc::new(event),
+                }))
+            }
+            MESSAGE_REACTION_UPDATED => {
+                let payload: MessageReactionUpdated = serde_json::from_str(payload)?;
+                let user_ids =
+                    fetch_recipients(payload.change.chat_id, payload.parent_id, state).await?;
+                Ok(Self::from_message_reaction_updated(payload, user_ids))
+            }
+            CHAT_READ => {
+                // only the devices of the reader care
+                let payload: ChatReadUpdated = serde_json::from_str(payload)?;
+                Ok(Some(Self {
+                    user_ids: HashSet::from([payload.user_id]),
+                    event: Arc::new(AppEvent::ReadChat(payload.read)),
+                }))
+            }
+            CHAT_TYPING => {
+                let typing: Typing = serde_json::from_str(payload)?;
+                let members = fetch_recipients(typing.chat_id, None, state).await?;
+                Ok(Self::from_chat_typing(typing, members))
+            }
+            _ => anyhow::bail!("unknown channel: {}", channel),
+        }
+    }
+
+    /// Removed members need to know as well, they come in `users`
+    fn from_chat_updated(payload: ChatUpdated, chat: Chat) -> Option<Self> {
+        let mut user_ids: HashSet<i64> = payload.users.into_iter().collect();
+        if payload.chunk == 0 {
+            user_ids.extend(members(&chat));
+        }
+        let event = match payload.op.as_str() {
+            "INSERT" => AppEvent::NewChat(chat),
+            "UPDATE" => AppEvent::UpdateChat(chat),
+            "DELETE" => AppEvent::DeleteChat(chat),
+            _ => return None,
+        };
+
+        Some(Self {
+            user_ids,
+            event: Arc::new(event),
+        })
+    }
+
+    /// The members of the chat and the affected users, who may no longer be members
+    fn from_chat_member_updated(payload: ChatMemberUpdated, chat: Chat) -> Option<Self> {
+        let user_ids = members(&chat)
+            .union(&payload.user_ids.iter().copied().collect())
+            .copied()
+            .collect();
+        let change = ChatMembersChanged {
+            chat,
+            user_ids: payload.user_ids,
+            role: payload.role,
+            by: payload.by,
+        };
+        let event = match payload.op.as_str() {
+            "ADD" => AppEvent::AddMembers(change),
+            "REMOVE" => AppEvent::RemoveMembers(change),
+            "ROLE" => AppEvent::UpdateMemberRole(change),
+            _ => return None,
+        };
+
+        Some(Self {
+            user_ids,
+            event: Arc::new(event),
+        })
+    }
+
+    /// The other members, the typist knows already
+    fn from_chat_typing(typing: Typing, members: HashSet<i64>) -> Option<Self> {
+        let user_ids: HashSet<i64> = members
+            .into_iter()
+            .filter(|id| *id != typing.user_id)
+            .collect();
+        if user_ids.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            user_ids,
+            event: Arc::new(AppEvent::Typing(typing)),
+        })
+    }
+
+    fn from_message_reaction_updated(
+        payload: MessageReactionUpdated,
+        user_ids: HashSet<i64>,
+    ) -> Option<Self> {
+        let event = match payload.op.as_str() {
+            "ADD" => AppEvent::AddReaction(payload.change),
+            "REMOVE" => AppEvent::RemoveReaction(payload.change),
+            _ => return None,
+        };
+
+        Some(Self {
+            user_ids,
+            event: Arc::new(event),
+        })
+    }
+}
+
+impl AppState {
+    pub(crate) fn notify(&self, notification: Notification) {
+        // kept before it's sent, a stream subscribing in between gets it twice
+        // and skips the live one by its id
+        let event = self
+            .replay
+            .record(&notification.user_ids, notification.event, Utc::now());
+        for user_id in notification.user_ids {
+            let Some(tx) = self.users.get(&user_id).map(|tx| tx.clone()) else {
+                continue;
+            };
+            if tx.send(event.clone()).is_err() {
+                // all connections of this user are gone
+                self.users
+                    .remove_if(&user_id, |_, tx| tx.receiver_count() == 0);
+            }
+        }
+    }
+}
+
+async fn fetch_message(id: i64, state: &AppState) -> Result<Option<Message>> {
+    let mut message: Option<Message> = sqlx::query_as(
+        r#"
+            SELECT id,chat_id,sender_id,content,COALESCE(images,'{}') AS images,created_at,
+                parent_id,updated_at,deleted_at
+            FROM messages
+            WHERE id = $1
+        "#,
+    )
+    .bind(id)
+    .fetch_optional(&state.pool)
+    .await?;
+    if let Some(message) = message.as_mut() {
+        Message::load_attachments(&state.pool, std::slice::from_mut(message)).await?;
+    }
+
+    Ok(message)
+}
+
+/// The chat with its members, in the shape chat_server sends it
+async fn fetch_chat(id: i64, state: &AppState) -> Result<Option<Chat>> {
+    let chat = sqlx::query_as(
+        r#"
+            SELECT c.id,c.ws_id,c.name,c.type,
+                ARRAY(SELECT m.user_id FROM chat_members m WHERE m.chat_id = c.id ORDER BY m.user_id) AS members,
+                c.created_at
+            FROM chats c
+            WHERE c.id = $1
+        "#,
+    )
+    .bind(id)
+    .fetch_optional(&state.pool)
+    .await?;
+
+    Ok(chat)
+}
+
+/// The members of the chat, or for a reply the ones who took part in its thread
+async fn fetch_recipients(
+    chat_id: i64,
+    parent_id: Option<i64>,
+    state: &AppState,
+) -> Result<HashSet<i64>> {
+    let (user_ids,): (Vec<i64>,) = sqlx::query_as(r#"SELECT message_recipients($1, $2)"#)
+        .bind(chat_id)
+        .bind(parent_id)
+        .fetch_one(&state.pool)
+        .await?;
+
+    Ok(user_ids.into_iter().collect())
+}
+
+fn members(chat: &Chat) -> HashSet<i64> {
+    chat.members.iter().copied().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(members: &[i64]) -> Chat {
+        serde_json::from_value(serde_json::json!({
+            "id": 1, "ws_id": 1, "name": "general", "type": "group", "members": members,
+            "created_at": "2024-06-28T09:30:00.123456+00:00"
+        }))
+        .expect("valid chat")
+    }
+
+    #[test]
+    fn chat_updated_should_notify_removed_and_current_members() -> Result<()> {
+        let payload = r#"{"op": "UPDATE", "id": 1, "users": [3], "chunk": 0}"#;
+        let notification =
+            Notification::from_chat_updated(serde_json::from_str(payload)?, chat(&[1, 2, 4]))
+                .expect("should be a notification");
+
+        assert_eq!(notification.user_ids, HashSet::from([1, 2, 3, 4]));
+        assert_eq!(notification.event.name(), "UpdateChat");
+
+        // further chunks of removed users don't go to the members again
+        let payload = r#"{"op": "UPDATE", "id": 1, "users": [5], "chunk": 1}"#;
+        let notification =
+            Notification::from_chat_updated(serde_json::from_str(payload)?, chat(&[1, 2, 4]))
+                .expect("should be a notification");
+        assert_eq!(notification.user_ids, HashSet::from([5]));
+        Ok(())
+    }
+
+    #[test]
+    fn chat_deleted_should_notify_old_members() -> Result<()> {
+        let payload = r#"{
+            "op": "DELETE",
+            "id": 1,
+            "chat": {"id": 1, "ws_id": 1, "name": "pair", "type": "single", "members": [], "created_at": "2024-06-28T09:30:00+00:00"},
+            "users": [1, 2],
+            "chunk": 0
+        }"#;
+        let mut payload: ChatUpdated = serde_json::from_str(payload)?;
+        let chat = payload.chat.take().expect("deleted chat is sent");
+        let notification =
+            Notification::from_chat_updated(payload, chat).expect("should be a notification");
+
+        assert_eq!(notification.user_ids, HashSet::from([1, 2]));
+        assert!(matches!(&*notification.event, AppEvent::DeleteChat(chat) if chat.id == 1));
+        Ok(())
+    }
+
+    #[test]
+    fn removed_members_should_be_notified() -> Result<()> {
+        let payload =
+            r#"{"op": "REMOVE", "chat_id": 1, "user_ids": [3], "role": null, "by": 1, "chunk": 0}"#;
+        let notification =
+            Notification::from_chat_member_updated(serde_json::from_str(payload)?, chat(&[1, 2]))
+                .expect("should be a notification");
+
+        assert_eq!(notification.user_ids, HashSet::from([1, 2, 3]));
+        assert!(
+            matches!(&*notification.event, AppEvent::RemoveMembers(change) if change.user_ids == vec![3])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reaction_should_notify_message_recipients() -> Result<()> {
+        let payload = r#"{
+            "op": "ADD",
+            "chat_id": 1,
+            "message_id": 7,
+            "emoji": "🎉",
+            "user_id": 2,
+            "count": 3,
+            "parent_id": null
+        }"#;
+        let notification = Notification::from_message_reaction_updated(
+            serde_json::from_str(payload)?,
+            HashSet::from([1, 2]),
+        )
+        .expect("should be a notification");
+
+        assert_eq!(notification.user_ids, HashSet::from([1, 2]));
+        assert!(matches!(
+            &*notification.event,
+            AppEvent::AddReaction(change) if change.emoji == "🎉" && change.count == 3
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn typing_should_notify_other_members() -> Result<()> {
+        let typing: Typing = serde_json::from_str(r#"{"chat_id": 1, "user_id": 2}"#)?;
+        let notification = Notification::from_chat_typing(typing.clone(), HashSet::from([1, 2, 3]))
+            .expect("should be a notification");
+
+        assert_eq!(notification.user_ids, HashSet::from([1, 3]));
+        assert_eq!(notification.event.name(), "Typing");
+
+        assert!(Notification::from_chat_typing(typing, HashSet::from([2])).is_none());
+        Ok(())
+    }
+}
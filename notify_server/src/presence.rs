@@ -0,0 +1,293 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Query, State},
+    Extension, Json,
+};
+use chat_core::User;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{config::PresenceConfig, notify::Notification, AppError, AppEvent, AppState};
+
+/// how often presence is checked for users whose connections dropped
+const TICK: Duration = Duration::from_secs(1);
+/// most users looked up at once
+const MAX_LOOKUP: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+    /// connected and not idle, or dropped for less than the grace period, e.g. reconnecting
+    Online,
+    /// not connected for a little while, or every connection said it's idle,
+    /// e.g. the app went to the background
+    Away,
+    Offline,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserPresence {
+    pub user_id: i64,
+    pub status: PresenceStatus,
+    /// when the user was last connected, unknown after a restart of the server
+    pub last_seen_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    connections: usize,
+    /// connections whose client said the user is away
+    idle: usize,
+    last_seen_at: DateTime<Utc>,
+    /// the status the peers of the user were last told about
+    published: PresenceStatus,
+}
+
+/// Presence of the users from the event streams of this server. Users that
+/// are offline are not kept
+pub struct PresenceTracker {
+    grace_period: chrono::Duration,
+    offline_after: chrono::Duration,
+    users: DashMap<i64, Entry>,
+}
+
+impl PresenceTracker {
+    pub fn new(config: &PresenceConfig) -> Self {
+        Self {
+            grace_period: chrono::Duration::seconds(config.grace_period as i64),
+            offline_after: chrono::Duration::seconds(config.offline_after as i64),
+            users: DashMap::new(),
+        }
+    }
+
+    /// A stream of the user opened, true if they came online with it
+    pub fn connect(&self, user_id: i64, now: DateTime<Utc>) -> bool {
+        let mut entry = self.users.entry(user_id).or_insert(Entry {
+            connections: 0,
+            idle: 0,
+            last_seen_at: now,
+            published: PresenceStatus::Offline,
+        });
+        entry.connections += 1;
+        entry.last_seen_at = now;
+        let changed = entry.published != PresenceStatus::Online;
+        entry.published = PresenceStatus::Online;
+        changed
+    }
+
+    /// A stream of the user dropped, they stay online for the grace period
+    pub fn disconnect(&self, user_id: i64, now: DateTime<Utc>) {
+        if let Some(mut entry) = self.users.get_mut(&user_id) {
+            entry.connections = entry.connections.saturating_sub(1);
+            entry.idle = entry.idle.min(entry.connections);
+            entry.last_seen_at = now;
+        }
+    }
+
+    /// A connection of the user went idle or became active again, the change is
+    /// published with the next tick
+    pub fn set_idle(&self, user_id: i64, idle: bool, now: DateTime<Utc>) {
+        if let Some(mut entry) = self.users.get_mut(&user_id) {
+            entry.idle = if idle {
+                (entry.idle + 1).min(entry.connections)
+            } else {
+                entry.idle.saturating_sub(1)
+            };
+            entry.last_seen_at = now;
+        }
+    }
+
+    pub fn get(&self, user_id: i64, now: DateTime<Utc>) -> UserPresence {
+        match self.users.get(&user_id) {
+            Some(entry) => UserPresence {
+                user_id,
+                status: self.status(&entry, now),
+                last_seen_at: Some(entry.last_seen_at),
+            },
+            None => UserPresence {
+                user_id,
+                status: PresenceStatus::Offline,
+                last_seen_at: None,
+            },
+        }
+    }
+
+    /// Users whose status changed with time since they were last published,
+    /// which are marked as published. Users gone offline are dropped
+    pub fn changes(&self, now: DateTime<Utc>) -> Vec<UserPresence> {
+        let mut changes = Vec::new();
+        self.users.retain(|user_id, entry| {
+            let status = self.status(entry, now);
+            if status != entry.published {
+                entry.published = status;
+                changes.push(UserPresence {
+                    user_id: *user_id,
+                    status,
+                    last_seen_at: Some(entry.last_seen_at),
+                });
+            }
+            status != PresenceStatus::Offline
+        });
+        changes
+    }
+
+    fn status(&self, entry: &Entry, now: DateTime<Utc>) -> PresenceStatus {
+        let gone = now - entry.last_seen_at;
+        if entry.connections > entry.idle || (entry.connections == 0 && gone < self.grace_period) {
+            PresenceStatus::Online
+        } else if entry.connections > 0 || gone < self.offline_after {
+            PresenceStatus::Away
+        } else {
+            PresenceStatus::Offline
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct PresenceQuery {
+    /// comma separated user ids
+    ids: String,
+}
+
+/// Presence of users of the workspace, others are left out
+pub(crate) async fn presence_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(query): Query<PresenceQuery>,
+) -> Result<Json<Vec<UserPresence>>, AppError> {
+    let ids = query
+        .ids
+        .split(',')
+        .filter(|s| !s.is_empty())
+        .map(|s| s.trim().parse::<i64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| AppError::InvalidInput(format!("ids: {}", e)))?;
+    if ids.len() > MAX_LOOKUP {
+        return Err(AppError::InvalidInput(format!(
+            "at most {} ids at once",
+            MAX_LOOKUP
+        )));
+    }
+
+    let ids: Vec<(i64,)> =
+        sqlx::query_as(r#"SELECT id FROM users WHERE id = ANY($1) AND ws_id = $2 ORDER BY id"#)
+            .bind(&ids)
+            .bind(user.ws_id)
+            .fetch_all(&state.pool)
+            .await?;
+    let now = Utc::now();
+    let presence = ids
+        .into_iter()
+        .map(|(id,)| state.presence.get(id, now))
+        .collect();
+    Ok(Json(presence))
+}
+
+/// Publish the users going away and offline as time passes
+pub fn setup_presence_ticker(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(TICK);
+        loop {
+            interval.tick().await;
+            for presence in state.presence.changes(Utc::now()) {
+                publish_presence(&state, presence).await;
+            }
+        }
+    });
+}
+
+/// Tell the users who share a chat with the user about their presence
+pub(crate) async fn publish_presence(state: &AppState, presence: UserPresence) {
+    let peers: Result<Vec<(i64,)>, _> = sqlx::query_as(
+        r#"
+            SELECT DISTINCT peer.user_id
+            FROM chat_members cm
+            JOIN chat_members peer ON peer.chat_id = cm.chat_id
+            WHERE cm.user_id = $1 AND peer.user_id <> $1
+        "#,
+    )
+    .bind(presence.user_id)
+    .fetch_all(&state.pool)
+    .await;
+    let peers: HashSet<i64> = match peers {
+        Ok(peers) => peers.into_iter().map(|(id,)| id).collect(),
+        Err(e) => {
+            warn!("Failed to load peers of user {}: {:?}", presence.user_id, e);
+            return;
+        }
+    };
+
+    state.notify(Notification {
+        user_ids: peers,
+        event: Arc::new(AppEvent::Presence(presence)),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker() -> PresenceTracker {
+        PresenceTracker::new(&PresenceConfig {
+            grace_period: 10,
+            offline_after: 300,
+        })
+    }
+
+    #[test]
+    fn presence_should_survive_reconnects() {
+        let tracker = tracker();
+        let t0 = Utc::now();
+        let at = |secs| t0 + chrono::Duration::seconds(secs);
+
+        assert!(tracker.connect(1, t0));
+        // a second device doesn't change anything
+        assert!(!tracker.connect(1, t0));
+        tracker.disconnect(1, at(1));
+        tracker.disconnect(1, at(2));
+
+        // dropped for less than the grace period, and back
+        assert!(tracker.changes(at(5)).is_empty());
+        assert!(!tracker.connect(1, at(6)));
+        tracker.disconnect(1, at(7));
+        assert_eq!(tracker.get(1, at(8)).status, PresenceStatus::Online);
+
+        let changes = tracker.changes(at(20));
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].status, PresenceStatus::Away);
+        assert_eq!(changes[0].last_seen_at, Some(at(7)));
+        assert!(tracker.changes(at(21)).is_empty());
+
+        let changes = tracker.changes(at(400));
+        assert_eq!(changes[0].status, PresenceStatus::Offline);
+        // offline users are not kept
+        assert!(tracker.users.is_empty());
+        assert_eq!(tracker.get(1, at(400)).last_seen_at, None);
+
+        assert!(tracker.connect(1, at(500)));
+    }
+
+    #[test]
+    fn user_should_be_away_when_all_connections_are_idle() {
+        let tracker = tracker();
+        let t0 = Utc::now();
+        let at = |secs| t0 + chrono::Duration::seconds(secs);
+
+        tracker.connect(1, t0);
+        tracker.connect(1, t0);
+        tracker.set_idle(1, true, at(1));
+        assert!(tracker.changes(at(2)).is_empty());
+        tracker.set_idle(1, true, at(3));
+        let changes = tracker.changes(at(4));
+        assert_eq!(changes[0].status, PresenceStatus::Away);
+
+        // connected users stay away rather than going offline
+        assert!(tracker.changes(at(400)).is_empty());
+        tracker.set_idle(1, false, at(401));
+        let changes = tracker.changes(at(402));
+        assert_eq!(changes[0].status, PresenceStatus::Online);
+    }
+}
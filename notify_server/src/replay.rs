@@ -0,0 +1,220 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+
+use crate::{config::SseConfig, AppEvent, AppState};
+
+/// how often the buffers of users who went away are dropped
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// An event as sent on the streams, `id` goes out as the SSE id
+#[derive(Debug, Clone)]
+pub struct StreamEvent {
+    pub id: i64,
+    pub event: Arc<AppEvent>,
+}
+
+#[derive(Debug)]
+struct UserEvents {
+    events: VecDeque<StreamEvent>,
+    /// every event of the user after this id is in `events`
+    complete_after: i64,
+    updated_at: DateTime<Utc>,
+}
+
+/// Recent events of the users, so that a reconnecting stream picks up from its
+/// `Last-Event-ID`. Ephemeral events get an id but are not kept
+pub struct ReplayBuffer {
+    /// ids start from the startup time in micros, so ids from before a restart
+    /// are never taken for ids of this run
+    first_id: i64,
+    last_id: AtomicI64,
+    /// the newest event dropped with the buffer of a user
+    pruned: AtomicI64,
+    capacity: usize,
+    retention: chrono::Duration,
+    users: DashMap<i64, UserEvents>,
+}
+
+impl ReplayBuffer {
+    pub fn new(config: &SseConfig) -> Self {
+        let first_id = Utc::now().timestamp_micros();
+        Self {
+            first_id,
+            last_id: AtomicI64::new(first_id),
+            pruned: AtomicI64::new(first_id),
+            capacity: config.replay_capacity,
+            retention: chrono::Duration::seconds(config.replay_retention as i64),
+            users: DashMap::new(),
+        }
+    }
+
+    /// The id of the latest event
+    pub fn last_id(&self) -> i64 {
+        self.last_id.load(Ordering::SeqCst)
+    }
+
+    /// Give the event the next id and keep it for its users
+    pub fn record(
+        &self,
+        user_ids: &HashSet<i64>,
+        event: Arc<AppEvent>,
+        now: DateTime<Utc>,
+    ) -> StreamEvent {
+        let id = self.last_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let event = StreamEvent { id, event };
+        let keep = self.capacity > 0 && !event.event.is_ephemeral();
+        for user_id in user_ids {
+            let mut entry = self.users.entry(*user_id).or_insert_with(|| UserEvents {
+                events: VecDeque::new(),
+                complete_after: self.pruned.load(Ordering::SeqCst),
+                updated_at: now,
+            });
+            entry.updated_at = now;
+            if !keep {
+                continue;
+            }
+            if entry.events.len() >= self.capacity {
+                if let Some(dropped) = entry.events.pop_front() {
+                    entry.complete_after = entry.complete_after.max(dropped.id);
+                }
+            }
+            entry.events.push_back(event.clone());
+        }
+        event
+    }
+
+    /// The events of the user after `last_id`, oldest first. None when some of
+    /// them are gone, or the id is not from this run, and the client has to reload
+    pub fn since(&self, user_id: i64, last_id: i64) -> Option<Vec<StreamEvent>> {
+        if last_id < self.first_id || last_id > self.last_id() {
+            return None;
+        }
+        match self.users.get(&user_id) {
+            Some(entry) if last_id >= entry.complete_after => {
+                let mut events: Vec<_> = entry
+                    .events
+                    .iter()
+                    .filter(|e| e.id > last_id)
+                    .cloned()
+                    .collect();
+                events.sort_by_key(|e| e.id);
+                Some(events)
+            }
+            Some(_) => None,
+            // nothing was kept for the user, unless it was pruned since
+            None => (last_id >= self.pruned.load(Ordering::SeqCst)).then(Vec::new),
+        }
+    }
+
+    /// Drop the buffers of the users who are not connected and got nothing
+    /// for the retention period
+    pub fn prune(&self, now: DateTime<Utc>, connected: impl Fn(i64) -> bool) {
+        self.users.retain(|user_id, entry| {
+            if connected(*user_id) || now - entry.updated_at < self.retention {
+                return true;
+            }
+            let newest = entry.events.back().map_or(entry.complete_after, |e| e.id);
+            self.pruned.fetch_max(newest, Ordering::SeqCst);
+            false
+        });
+    }
+}
+
+pub fn setup_replay_pruner(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            state.replay.prune(Utc::now(), |user_id| {
+                state
+                    .users
+                    .get(&user_id)
+                    .is_some_and(|tx| tx.receiver_count() > 0)
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_core::Typing;
+
+    fn buffer(capacity: usize) -> ReplayBuffer {
+        ReplayBuffer::new(&SseConfig {
+            replay_capacity: capacity,
+            replay_retention: 300,
+            ..Default::default()
+        })
+    }
+
+    fn typing(user_id: i64) -> Arc<AppEvent> {
+        Arc::new(AppEvent::Typing(Typing {
+            chat_id: 1,
+            user_id,
+        }))
+    }
+
+    fn read_chat(chat_id: i64) -> Arc<AppEvent> {
+        Arc::new(AppEvent::ReadChat(chat_core::ChatRead {
+            chat_id,
+            last_read_message_id: None,
+            unread_count: 0,
+        }))
+    }
+
+    #[test]
+    fn missed_events_should_be_replayed() {
+        let buffer = buffer(2);
+        let now = Utc::now();
+        let start = buffer.last_id();
+
+        let first = buffer.record(&HashSet::from([1, 2]), read_chat(1), now);
+        let typing = buffer.record(&HashSet::from([1]), typing(2), now);
+        let second = buffer.record(&HashSet::from([1]), read_chat(2), now);
+        assert!(first.id < typing.id && typing.id < second.id);
+
+        let ids = |events: Vec<StreamEvent>| events.iter().map(|e| e.id).collect::<Vec<_>>();
+        // typing is not kept
+        assert_eq!(
+            ids(buffer.since(1, start).unwrap()),
+            vec![first.id, second.id]
+        );
+        assert_eq!(ids(buffer.since(1, first.id).unwrap()), vec![second.id]);
+        assert_eq!(ids(buffer.since(2, first.id).unwrap()), Vec::<i64>::new());
+        assert!(buffer.since(3, start).unwrap().is_empty());
+
+        // the first event falls out for user 1 only
+        buffer.record(&HashSet::from([1]), read_chat(3), now);
+        assert!(buffer.since(1, start).is_none());
+        assert_eq!(buffer.since(1, first.id).unwrap().len(), 2);
+        assert_eq!(buffer.since(2, start).unwrap().len(), 1);
+
+        // ids from before a restart, or never handed out
+        assert!(buffer.since(1, start - 1).is_none());
+        assert!(buffer.since(1, buffer.last_id() + 1).is_none());
+    }
+
+    #[test]
+    fn pruned_users_should_need_a_reload() {
+        let buffer = buffer(8);
+        let now = Utc::now();
+        let start = buffer.last_id();
+        let event = buffer.record(&HashSet::from([1, 2]), read_chat(1), now);
+
+        let later = now + chrono::Duration::seconds(301);
+        buffer.prune(later, |user_id| user_id == 2);
+        assert!(buffer.since(1, start).is_none());
+        assert!(buffer.since(1, event.id).unwrap().is_empty());
+        assert_eq!(buffer.since(2, start).unwrap().len(), 1);
+    }
+}
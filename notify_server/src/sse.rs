@@ -1,26 +1,45 @@
-use std::{convert::Infallible, time::Duration};
+use std::time::Duration;
 
-use axum::response::{sse::Event, Sse};
-use axum_extra::{headers, TypedHeader};
-use futures::{stream, Stream};
+use axum::{
+    extract::State,
+    http::HeaderMap,
+    response::{sse::Event, IntoResponse, Sse},
+    Extension,
+};
+use chat_core::User;
 use tokio_stream::StreamExt;
 
+use crate::{connection::subscribe, AppEvent, AppState};
+
 pub(crate) async fn sse_handler(
-    TypedHeader(user_agent): TypedHeader<headers::UserAgent>,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    println!("`{}` connected", user_agent.as_str());
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    // sent by EventSource when it reconnects
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<i64>().ok());
+    let stream = match subscribe(&state, user.id, last_event_id) {
+        Ok(stream) => stream.map(|(id, event)| to_sse(&event, id)),
+        Err(e) => return e.into_response(),
+    };
 
-    // A `Stream` that repeats an event every second
-    //
-    // You can also create streams from tokio channels using the wrappers in
-    // https://docs.rs/tokio-stream
-    let stream = stream::repeat_with(|| Event::default().data("hi!"))
-        .map(Ok)
-        .throttle(Duration::from_secs(1));
+    Sse::new(stream)
+        .keep_alive(
+            axum::response::sse::KeepAlive::new()
+                .interval(Duration::from_secs(state.config.sse.keep_alive))
+                .text("keep-alive-text"),
+        )
+        .into_response()
+}
 
-    Sse::new(stream).keep_alive(
-        axum::response::sse::KeepAlive::new()
-            .interval(Duration::from_secs(1))
-            .text("keep-alive-text"),
-    )
+fn to_sse(event: &AppEvent, id: Option<i64>) -> Result<Event, axum::Error> {
+    let sse = Event::default().event(event.name());
+    let sse = match id {
+        Some(id) => sse.id(id.to_string()),
+        None => sse,
+    };
+    sse.json_data(event)
 }
@@ -0,0 +1,278 @@
+use std::{
+    pin::pin,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        Query, State, WebSocketUpgrade,
+    },
+    response::{IntoResponse, Response},
+    Extension,
+};
+use chat_core::{ChatRead, Typing, User};
+use chrono::Utc;
+use futures::{SinkExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::{
+    connection::{subscribe, Outgoing},
+    presence::PresenceStatus,
+    AppError, AppEvent, AppState,
+};
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct WsQuery {
+    /// the id of the last event received, WebSocket in browsers can't set headers
+    last_event_id: Option<i64>,
+}
+
+/// Frames sent by the client, tagged like the events with `event`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "event")]
+enum ClientFrame {
+    /// the user is typing in the chat, rate limited like `POST /api/chat/:id/typing`
+    Typing { chat_id: i64 },
+    /// `away` when the app goes to the background, `online` when it's back
+    Presence { status: PresenceStatus },
+    /// the user read up to the message, like `POST /api/chat/:id/read`
+    ReadChat { chat_id: i64, message_id: i64 },
+}
+
+/// An event as sent on the socket, with the same id, name and data as on `/events`
+#[derive(Debug, Serialize)]
+struct ServerFrame<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<i64>,
+    event: &'static str,
+    data: &'a AppEvent,
+}
+
+/// Delivers the same events as `/events`, and takes typing, presence and read
+/// frames from the client
+pub(crate) async fn ws_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(query): Query<WsQuery>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let events = match subscribe(&state, user.id, query.last_event_id) {
+        Ok(events) => events,
+        Err(e) => return e.into_response(),
+    };
+    ws.on_upgrade(move |socket| handle_socket(socket, state, user, events))
+}
+
+async fn handle_socket(
+    socket: WebSocket,
+    state: AppState,
+    user: User,
+    events: impl Stream<Item = Outgoing>,
+) {
+    let config = &state.config.ws;
+    let timeout = Duration::from_secs(config.timeout);
+    let period = Duration::from_secs(config.ping_interval);
+    let mut ping = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    let mut last_seen = Instant::now();
+    let mut idle = false;
+    let (mut sender, mut receiver) = socket.split();
+    let mut events = pin!(events);
+
+    loop {
+        let reply = tokio::select! {
+            event = events.next() => {
+                let Some((id, event)) = event else {
+                    break;
+                };
+                let frame = ServerFrame {
+                    id,
+                    event: event.name(),
+                    data: &event,
+                };
+                match serde_json::to_string(&frame) {
+                    Ok(text) => Message::Text(text),
+                    Err(e) => {
+                        warn!("Failed to serialize event: {:?}", e);
+                        continue;
+                    }
+                }
+            }
+            frame = receiver.next() => {
+                let Some(Ok(frame)) = frame else {
+                    break;
+                };
+                last_seen = Instant::now();
+                let ret = match frame {
+                    Message::Text(text) => handle_frame(&state, &user, &text, &mut idle).await,
+                    Message::Binary(_) => Err(AppError::InvalidInput(
+                        "binary frames are not supported".to_string(),
+                    )),
+                    // pings are answered by axum
+                    Message::Ping(_) | Message::Pong(_) => Ok(()),
+                    Message::Close(_) => break,
+                };
+                match ret {
+                    Ok(()) => continue,
+                    Err(e) => Message::Text(
+                        json!({"event": "Error", "data": {"error": e.to_string()}}).to_string(),
+                    ),
+                }
+            }
+            _ = ping.tick() => {
+                if last_seen.elapsed() > timeout {
+                    info!("User {} timed out", user.id);
+                    break;
+                }
+                Message::Ping(Vec::new())
+            }
+        };
+        if sender.send(reply).await.is_err() {
+            break;
+        }
+    }
+
+    // the connection is gone, it's not idle anymore
+    if idle {
+        state.presence.set_idle(user.id, false, Utc::now());
+    }
+}
+
+async fn handle_frame(
+    state: &AppState,
+    user: &User,
+    text: &str,
+    idle: &mut bool,
+) -> Result<(), AppError> {
+    let frame: ClientFrame =
+        serde_json::from_str(text).map_err(|e| AppError::InvalidInput(e.to_string()))?;
+    match frame {
+        ClientFrame::Typing { chat_id } => send_typing(state, user, chat_id).await,
+        ClientFrame::Presence { status } => {
+            let away = match status {
+                PresenceStatus::Online => false,
+                PresenceStatus::Away => true,
+                PresenceStatus::Offline => {
+                    return Err(AppError::InvalidInput(
+                        "close the socket to go offline".to_string(),
+                    ))
+                }
+            };
+            if away != *idle {
+                *idle = away;
+                state.presence.set_idle(user.id, away, Utc::now());
+            }
+            Ok(())
+        }
+        ClientFrame::ReadChat {
+            chat_id,
+            message_id,
+        } => mark_chat_read(state, user, chat_id, message_id).await,
+    }
+}
+
+async fn check_chat_member(state: &AppState, user: &User, chat_id: i64) -> Result<(), AppError> {
+    let (is_member,): (bool,) = sqlx::query_as(
+        r#"
+            SELECT EXISTS(
+                SELECT 1 FROM chat_members cm JOIN chats c ON c.id = cm.chat_id
+                WHERE cm.chat_id = $1 AND cm.user_id = $2 AND c.ws_id = $3
+            )
+        "#,
+    )
+    .bind(chat_id)
+    .bind(user.id)
+    .bind(user.ws_id)
+    .fetch_one(&state.pool)
+    .await?;
+    if !is_member {
+        return Err(AppError::PermissionDenied(format!(
+            "user {} is not a member of chat {}",
+            user.id, chat_id
+        )));
+    }
+    Ok(())
+}
+
+/// Goes through the listener like the typing of chat_server, so that the
+/// members connected to other instances get it too
+async fn send_typing(state: &AppState, user: &User, chat_id: i64) -> Result<(), AppError> {
+    if !state.typing_limiter.check(user.id) {
+        return Err(AppError::TooManyRequests(format!(
+            "user {} is typing too fast",
+            user.id
+        )));
+    }
+    check_chat_member(state, user, chat_id).await?;
+    Typing {
+        chat_id,
+        user_id: user.id,
+    }
+    .notify(&state.pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Move the read cursor forward, shared with chat_server. The devices of
+/// the user get the `ReadChat` event through the listener
+async fn mark_chat_read(
+    state: &AppState,
+    user: &User,
+    chat_id: i64,
+    message_id: i64,
+) -> Result<(), AppError> {
+    check_chat_member(state, user, chat_id).await?;
+
+    let mut tx = state.pool.begin().await?;
+    let (read, moved) = ChatRead::mark(&mut tx, chat_id, user.id, message_id).await?;
+    if !moved && read.last_read_message_id < Some(message_id) {
+        return Err(AppError::NotFound(format!("message id {}", message_id)));
+    }
+    tx.commit().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_frames_should_be_tagged_like_events() -> anyhow::Result<()> {
+        let frame: ClientFrame = serde_json::from_str(r#"{"event": "Typing", "chat_id": 1}"#)?;
+        assert!(matches!(frame, ClientFrame::Typing { chat_id: 1 }));
+        let frame: ClientFrame =
+            serde_json::from_str(r#"{"event": "Presence", "status": "away"}"#)?;
+        assert!(matches!(
+            frame,
+            ClientFrame::Presence {
+                status: PresenceStatus::Away
+            }
+        ));
+        let frame: ClientFrame =
+            serde_json::from_str(r#"{"event": "ReadChat", "chat_id": 1, "message_id": 7}"#)?;
+        assert!(matches!(
+            frame,
+            ClientFrame::ReadChat {
+                chat_id: 1,
+                message_id: 7
+            }
+        ));
+        assert!(serde_json::from_str::<ClientFrame>(r#"{"event": "NewChat"}"#).is_err());
+
+        let event = AppEvent::Resync;
+        let frame = ServerFrame {
+            id: Some(42),
+            event: event.name(),
+            data: &event,
+        };
+        assert_eq!(
+            serde_json::to_string(&frame)?,
+            r#"{"id":42,"event":"Resync","data":{"event":"Resync"}}"#
+        );
+        Ok(())
+    }
+}